@@ -0,0 +1,82 @@
+//! Benchmarks of finding commands with many libraries loaded.
+//!
+//! The libraries are registered in-process, so no plugins have to be built.
+
+use std::sync::Arc;
+use async_trait::async_trait;
+use bpp_command_api::{
+    structs::{Message, ServiceDirectory},
+    traits::Command,
+    userservice::user_service_client::UserServiceClient,
+    youtubeservice::you_tube_service_client::YouTubeServiceClient,
+    CommandError,
+};
+use commandservice::{
+    commandservice::{command_service_server::CommandService, CommandListRequest},
+    config::ProcessorConfig,
+    loader::{CommandProcessor, CommandServiceServer},
+};
+use criterion::{criterion_group, criterion_main, Criterion};
+use tonic::transport::Endpoint;
+
+const LIBRARIES: usize = 50;
+const COMMANDS_PER_LIBRARY: usize = 10;
+
+#[derive(Clone)]
+struct Noop;
+
+#[async_trait]
+impl Command for Noop {
+    async fn execute(&self, _message: Message, _service_directory: &mut ServiceDirectory) -> Result<(), CommandError> {
+        Ok(())
+    }
+}
+
+/// Builds a processor with `LIBRARIES` libraries of `COMMANDS_PER_LIBRARY` commands each,
+/// its clients connect lazily and are never used
+fn processor() -> Arc<CommandProcessor> {
+    let channel = Endpoint::from_static("http://[::1]:50051").connect_lazy().unwrap();
+    let processor = CommandProcessor::new(
+        YouTubeServiceClient::new(channel.clone()),
+        UserServiceClient::new(channel),
+        ProcessorConfig::default(),
+    );
+    for library in 0..LIBRARIES {
+        processor
+            .load_in_process(&format!("lib{}", library), |registrar| {
+                for command in 0..COMMANDS_PER_LIBRARY {
+                    registrar.register_command(&format!("cmd{}_{}", library, command), &[], Box::new(Noop));
+                }
+            })
+            .unwrap();
+    }
+    Arc::new(processor)
+}
+
+fn dispatch(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let processor = runtime.block_on(async { processor() });
+
+    c.bench_function("lookup", |b| b.iter(|| processor.lookup("cmd42_7")));
+    c.bench_function("lookup namespaced", |b| b.iter(|| processor.lookup("lib42::cmd42_7")));
+
+    let server = CommandServiceServer {
+        processor,
+        commands_directories: Vec::new(),
+    };
+    c.bench_function("get_commands", |b| {
+        b.to_async(&runtime)
+            .iter(|| server.get_commands(tonic::Request::new(CommandListRequest::default())))
+    });
+    c.bench_function("get_commands filtered", |b| {
+        b.to_async(&runtime).iter(|| {
+            server.get_commands(tonic::Request::new(CommandListRequest {
+                name_contains: "cmd42".to_string(),
+                ..Default::default()
+            }))
+        })
+    });
+}
+
+criterion_group!(benches, dispatch);
+criterion_main!(benches);
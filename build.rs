@@ -1,5 +1,11 @@
+use std::{env, path::PathBuf};
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("proto/commandservice.proto")?;
+    let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+
+    tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("commandservice_descriptor.bin"))
+        .compile(&["proto/commandservice.proto"], &["proto"])?;
 
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,55 @@
+use bpp_command_api::structs::PermissionLevel;
+
+/// Commands built into the processor to manage it from the chat, turned on
+/// with `CS_ADMIN_COMMANDS`.
+///
+/// Like the built-in help, commands of the libraries with the same name take
+/// precedence.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AdminCommand {
+    /// Lists the loaded libraries
+    Libraries,
+    /// Reloads a loaded library from disk
+    Reload,
+    Enable,
+    Disable,
+}
+
+impl AdminCommand {
+    /// Looks up a built-in admin command by its lowercase name
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "libraries" => Some(AdminCommand::Libraries),
+            "reload" => Some(AdminCommand::Reload),
+            "enable" => Some(AdminCommand::Enable),
+            "disable" => Some(AdminCommand::Disable),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AdminCommand::Libraries => "libraries",
+            AdminCommand::Reload => "reload",
+            AdminCommand::Enable => "enable",
+            AdminCommand::Disable => "disable",
+        }
+    }
+
+    /// Placeholder of the argument the command takes, `None` if it takes none
+    pub fn argument(self) -> Option<&'static str> {
+        match self {
+            AdminCommand::Libraries => None,
+            AdminCommand::Reload => Some("<library>"),
+            AdminCommand::Enable | AdminCommand::Disable => Some("<command>"),
+        }
+    }
+
+    /// Reloading runs code of the library, so it's reserved for owners
+    pub fn permission_level(self) -> PermissionLevel {
+        match self {
+            AdminCommand::Reload => PermissionLevel::Owner,
+            AdminCommand::Libraries | AdminCommand::Enable | AdminCommand::Disable => PermissionLevel::Moderator,
+        }
+    }
+}
@@ -0,0 +1,118 @@
+//! Splitting of command messages into arguments.
+//!
+//! The rules are:
+//! - Arguments are separated by runs of whitespace, leading and trailing
+//!   whitespace is ignored.
+//! - A double quote starts a quoted section that runs until the next
+//!   unescaped double quote, or the end of the message if there is none.
+//!   Whitespace inside a quoted section is part of the argument.
+//! - Quoted sections can be mixed with unquoted text, `a"b c"d` is the single
+//!   argument `ab cd`. An argument that only consists of quotes, like `""`,
+//!   is an empty argument.
+//! - A backslash escapes a double quote or another backslash, inside and
+//!   outside of quoted sections. A backslash in front of any other character
+//!   is kept as is.
+
+/// Splits a string into arguments, see the module documentation for the rules
+pub fn split(input: &str) -> Vec<String> {
+    let mut arguments = Vec::new();
+    let mut current = String::new();
+    // Tracks whether there is an argument, even if it's empty because it only consisted of quotes
+    let mut in_argument = false;
+    let mut quoted = false;
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if matches!(chars.peek(), Some('"') | Some('\\')) => {
+                current.push(chars.next().unwrap());
+                in_argument = true;
+            }
+            '"' => {
+                quoted = !quoted;
+                in_argument = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_argument {
+                    arguments.push(std::mem::take(&mut current));
+                    in_argument = false;
+                }
+            }
+            c => {
+                current.push(c);
+                in_argument = true;
+            }
+        }
+    }
+    if in_argument {
+        arguments.push(current);
+    }
+
+    arguments
+}
+
+/// Returns the command name of a command message, the first word after the prefix
+pub fn command_name<'a>(message: &'a str, prefix: &str) -> &'a str {
+    let rest = message.strip_prefix(prefix).unwrap_or(message).trim_start();
+    rest.split(char::is_whitespace).next().unwrap_or("")
+}
+
+/// Returns the arguments of a command message, everything after the prefix and command name
+pub fn command_arguments(message: &str, prefix: &str) -> Vec<String> {
+    let rest = message.strip_prefix(prefix).unwrap_or(message).trim_start();
+    let rest = rest
+        .find(char::is_whitespace)
+        .map_or("", |command_end| &rest[command_end..]);
+    split(rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_on_whitespace() {
+        assert_eq!(split("  a  b\tc "), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn empty_input_has_no_arguments() {
+        assert!(split("").is_empty());
+        assert!(split("   ").is_empty());
+    }
+
+    #[test]
+    fn quotes_keep_whitespace() {
+        assert_eq!(split(r#"say "hello world" now"#), vec!["say", "hello world", "now"]);
+        assert_eq!(split(r#"a"b c"d"#), vec!["ab cd"]);
+        assert_eq!(split(r#""" x"#), vec!["", "x"]);
+    }
+
+    #[test]
+    fn unterminated_quote_runs_to_the_end() {
+        assert_eq!(split(r#"a "b c"#), vec!["a", "b c"]);
+        assert_eq!(split(r#"a ""#), vec!["a", ""]);
+    }
+
+    #[test]
+    fn backslash_escapes_quotes_and_backslashes() {
+        assert_eq!(split(r#"\"a b\""#), vec![r#""a"#, r#"b""#]);
+        assert_eq!(split(r#""a \" b""#), vec![r#"a " b"#]);
+        assert_eq!(split(r#"a\\ b"#), vec![r#"a\"#, "b"]);
+        assert_eq!(split(r#"a\b"#), vec![r#"a\b"#]);
+    }
+
+    #[test]
+    fn command_name_is_the_first_word() {
+        assert_eq!(command_name("!roll 2d6", "!"), "roll");
+        assert_eq!(command_name("!  roll", "!"), "roll");
+        assert_eq!(command_name("!", "!"), "");
+    }
+
+    #[test]
+    fn command_arguments_skip_prefix_and_name() {
+        assert_eq!(command_arguments(r#"!say "hi there" you"#, "!"), vec!["hi there", "you"]);
+        assert!(command_arguments("!ping", "!").is_empty());
+        assert!(command_arguments("!", "!").is_empty());
+    }
+}
@@ -0,0 +1,25 @@
+use tonic::{Request, Status};
+
+/// Identity of a client that authenticated with a certificate.
+///
+/// Added to the request extensions, so RPC handlers can look it up with
+/// `request.extensions().get::<ClientIdentity>()`.
+#[derive(Clone, Debug)]
+pub struct ClientIdentity {
+    /// Subject of the client certificate, e.g. `CN=dashboard`
+    pub subject: String,
+}
+
+/// Interceptor that stores the identity of the client certificate on the request
+pub fn client_identity_interceptor(mut request: Request<()>) -> Result<Request<()>, Status> {
+    let subject = request.peer_certs().and_then(|certs| {
+        let cert = certs.first()?;
+        let (_, cert) = x509_parser::parse_x509_certificate(cert.get_ref()).ok()?;
+        Some(cert.subject().to_string())
+    });
+
+    if let Some(subject) = subject {
+        request.extensions_mut().insert(ClientIdentity { subject });
+    }
+    Ok(request)
+}
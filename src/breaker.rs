@@ -0,0 +1,61 @@
+use std::time::{Duration, Instant};
+use parking_lot::Mutex;
+
+#[derive(Default)]
+struct BreakerState {
+    consecutive_failures: u32,
+    /// Set while the breaker is open
+    open_until: Option<Instant>,
+}
+
+/// Disables a command after it failed too many times in a row.
+///
+/// Once the cooldown has passed the command is enabled again, starting over
+/// with a clean failure count.
+#[derive(Default)]
+pub struct CircuitBreaker {
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    /// Returns how much longer the command is disabled, `None` if it may run
+    pub fn check(&self) -> Option<Duration> {
+        let mut state = self.state.lock();
+        let open_until = state.open_until?;
+        let now = Instant::now();
+        if now < open_until {
+            return Some(open_until - now);
+        }
+
+        state.open_until = None;
+        state.consecutive_failures = 0;
+        None
+    }
+
+    /// Records the result of an execution, returns true if this failure opened the breaker.
+    ///
+    /// A `threshold` of zero never opens the breaker.
+    pub fn record(&self, success: bool, threshold: u32, cooldown: Duration) -> bool {
+        let mut state = self.state.lock();
+        if success {
+            state.consecutive_failures = 0;
+            return false;
+        }
+
+        state.consecutive_failures += 1;
+        if threshold == 0 || state.consecutive_failures < threshold || state.open_until.is_some() {
+            return false;
+        }
+        state.open_until = Some(Instant::now() + cooldown);
+        true
+    }
+
+    /// Returns the number of consecutive failures and how much longer the command is disabled
+    pub fn snapshot(&self) -> (u32, Option<Duration>) {
+        let state = self.state.lock();
+        let remaining = state
+            .open_until
+            .and_then(|open_until| open_until.checked_duration_since(Instant::now()));
+        (state.consecutive_failures, remaining)
+    }
+}
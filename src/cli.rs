@@ -0,0 +1,71 @@
+use std::{env, ffi::OsString, net::SocketAddr, path::PathBuf};
+use clap::{Parser, Subcommand};
+use log::LevelFilter;
+use crate::log::LogFilter;
+
+/// Runs commands from plugin libraries for ByersPlusPlus.
+///
+/// Every option can also be set through the environment variable listed with it.
+#[derive(Parser, Debug)]
+#[clap(version, about)]
+pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    /// Address of the YouTube service, required to serve
+    #[clap(long, env = "YTS_GRPC_ADDRESS")]
+    pub youtube_address: Option<String>,
+
+    /// Address of the user service, required to serve
+    #[clap(long, env = "US_GRPC_ADDRESS")]
+    pub user_address: Option<String>,
+
+    /// Address the gRPC server listens on
+    #[clap(long, env = "CS_GRPC_ADDRESS", default_value = "0.0.0.0:50051")]
+    pub listen: SocketAddr,
+
+    /// Unix domain socket the gRPC server listens on instead of --listen
+    #[clap(long, env = "CS_GRPC_UDS", parse(from_os_str))]
+    pub uds: Option<PathBuf>,
+
+    /// Directories to load command libraries from, multiple directories are
+    /// separated like in PATH
+    #[clap(long, env = "CS_COMMANDS_DIR", default_value = "commands", parse(from_os_str))]
+    pub commands_dir: OsString,
+
+    /// Log debug messages, a shortcut for --log-level debug. Setting DEBUG to
+    /// any value, even an empty one, does the same
+    #[clap(long)]
+    pub debug: bool,
+
+    /// Which messages to log, either a level like `trace` or directives per
+    /// module like `warn,commandservice::loader=debug`, takes
+    /// precedence over --debug
+    #[clap(long, env = "CS_LOG_LEVEL")]
+    pub log_level: Option<LogFilter>,
+}
+
+/// Runs instead of serving, without connecting to the other services
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Load and unload every library in the commands directories and report
+    /// which ones failed, exits with a nonzero code if any did
+    Validate,
+    /// Load every library and print the commands they register
+    List,
+}
+
+impl Cli {
+    /// Relative paths are resolved against the working directory
+    pub fn commands_directories(&self) -> Vec<PathBuf> {
+        env::split_paths(&self.commands_dir).collect()
+    }
+
+    pub fn log_filter(&self) -> LogFilter {
+        self.log_level.clone().unwrap_or_else(|| {
+            // DEBUG only has to be present, like before it could be set through the command line
+            let debug = self.debug || env::var_os("DEBUG").is_some();
+            LogFilter::level(if debug { LevelFilter::Debug } else { LevelFilter::Info })
+        })
+    }
+}
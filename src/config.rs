@@ -0,0 +1,460 @@
+use std::{env, fmt, net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
+use log::warn;
+use bpp_command_api::structs::PermissionLevel;
+use semver::VersionReq;
+use crate::{cli::Cli, quota::Quotas};
+
+/// Reads an environment variable and parses it, falling back to `default`
+/// if it's unset or can't be parsed.
+pub fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    let value = env::var(key);
+    if value.is_err() {
+        return default;
+    }
+    let value = value.unwrap();
+
+    let parsed = value.parse();
+    if parsed.is_err() {
+        warn!("Invalid value for {}: {}, using the default", key, value);
+        return default;
+    }
+    parsed.ok().unwrap()
+}
+
+/// What to do when a library registers a command name or alias that another
+/// library, or another command of the same library, already registered
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Refuse to load the library
+    Reject,
+    /// Load the library without the conflicting commands, within a library the
+    /// first command to register a name keeps it
+    Skip,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "reject" => Ok(ConflictPolicy::Reject),
+            "skip" => Ok(ConflictPolicy::Skip),
+            _ => Err(format!("unknown conflict policy {}", s)),
+        }
+    }
+}
+
+/// What to do with a message when the maximum number of commands is already running
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverloadPolicy {
+    /// Stop reading messages until a command finishes
+    Wait,
+    /// Drop the message
+    Drop,
+}
+
+impl FromStr for OverloadPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "wait" => Ok(OverloadPolicy::Wait),
+            "drop" => Ok(OverloadPolicy::Drop),
+            _ => Err(format!("unknown overload policy {}", s)),
+        }
+    }
+}
+
+/// Reads the command prefixes from `CS_PREFIXES` (comma-separated) or `CS_PREFIX`
+fn prefixes_from_env() -> Option<Vec<String>> {
+    if let Ok(prefixes) = env::var("CS_PREFIXES") {
+        let prefixes: Vec<String> = prefixes
+            .split(',')
+            .map(str::trim)
+            .filter(|prefix| !prefix.is_empty())
+            .map(str::to_string)
+            .collect();
+        if !prefixes.is_empty() {
+            return Some(prefixes);
+        }
+    }
+
+    env::var("CS_PREFIX").ok().map(|prefix| vec![prefix])
+}
+
+/// Reads the name of the built-in help command from `CS_HELP_COMMAND`,
+/// `CS_BUILTIN_HELP=0` turns the command off
+fn help_command_from_env(default: Option<String>) -> Option<String> {
+    if env::var("CS_BUILTIN_HELP").map_or(false, |enabled| enabled == "0") {
+        return None;
+    }
+    env::var("CS_HELP_COMMAND").ok().or(default)
+}
+
+/// Reads the permission level from which users bypass cooldowns and rate
+/// limits from `CS_BYPASS_LEVEL`, either `moderator` or `owner`
+fn bypass_level_from_env() -> Option<PermissionLevel> {
+    let level = env::var("CS_BYPASS_LEVEL").ok()?;
+    match level.trim().to_lowercase().as_str() {
+        "moderator" => Some(PermissionLevel::Moderator),
+        "owner" => Some(PermissionLevel::Owner),
+        _ => {
+            warn!("Invalid value for CS_BYPASS_LEVEL: {}, nobody bypasses cooldowns and rate limits", level);
+            None
+        }
+    }
+}
+
+/// Reads the core version requirement for libraries from `CS_CORE_VERSION_REQ`
+fn core_version_req_from_env() -> Option<VersionReq> {
+    let requirement = env::var("CS_CORE_VERSION_REQ").ok()?;
+    let parsed = requirement.parse();
+    if parsed.is_err() {
+        warn!("Invalid value for CS_CORE_VERSION_REQ: {}, requiring an exact core version", requirement);
+        return None;
+    }
+    Some(parsed.unwrap())
+}
+
+/// Settings for the [`crate::loader::CommandProcessor`]
+#[derive(Clone, Debug)]
+pub struct ProcessorConfig {
+    /// Delay before the first attempt to resubscribe to messages, at least a millisecond
+    pub reconnect_base: Duration,
+    /// Upper bound for the resubscribe delay
+    pub reconnect_max: Duration,
+    /// Cooldown for commands that don't declare their own, zero disables it
+    pub default_cooldown: Duration,
+    /// Commands per second a single user may invoke, zero disables rate limiting
+    pub user_rate: f64,
+    /// Number of commands a user may invoke in a burst
+    pub user_burst: u32,
+    /// Prefixes that mark a chat message as a command
+    pub prefixes: Vec<String>,
+    /// How command name collisions between libraries are handled
+    pub conflict_policy: ConflictPolicy,
+    /// Core versions libraries may be built against, `None` requires an exact match
+    pub core_version_req: Option<VersionReq>,
+    /// How long userservice lookups are cached, zero disables the cache
+    pub user_cache_ttl: Duration,
+    /// Maximum number of cached users
+    pub user_cache_size: usize,
+    /// How often a lookup of a user the userservice doesn't know yet, or
+    /// that couldn't reach the userservice, is retried
+    pub user_retries: u32,
+    /// Delay between retries of a user lookup, doubled after each retry while the userservice is unreachable
+    pub user_retry_delay: Duration,
+    /// Maximum number of messages being processed or queued behind messages of the same user
+    pub max_concurrency: usize,
+    /// What happens to messages while `max_concurrency` messages are processed
+    pub overload_policy: OverloadPolicy,
+    /// Consecutive failures after which a command is disabled, zero never disables commands
+    pub breaker_threshold: u32,
+    /// How long a command stays disabled
+    pub breaker_cooldown: Duration,
+    /// Whether unknown commands are answered with the closest known command
+    pub suggestions: bool,
+    /// Maximum edit distance of a suggested command
+    pub suggest_distance: usize,
+    /// Name of the built-in help command, `None` if it's turned off
+    pub help_command: Option<String>,
+    /// Whether the built-in admin commands are available, see [`crate::admin::AdminCommand`]
+    pub admin_commands: bool,
+    /// File the runtime state is persisted to, `None` keeps it in memory only
+    pub state_file: Option<PathBuf>,
+    /// How often the quota usage is saved to the state file if it changed, zero
+    /// only saves it on shutdown
+    pub state_save_interval: Duration,
+    /// Number of invocation events kept for subscribers that fall behind
+    pub invocation_event_capacity: usize,
+    /// How long a command may run before it's abandoned, `None` lets commands run indefinitely
+    pub command_timeout: Option<Duration>,
+    /// What happens to invocations of a command that is already running as often as it declared it may
+    pub command_overload_policy: OverloadPolicy,
+    /// Maximum number of blocking commands running at the same time
+    pub blocking_threads: usize,
+    /// How long `unload` waits for running commands of a library
+    pub unload_timeout: Duration,
+    /// How long `force_unload` waits for running commands of a library
+    pub force_unload_timeout: Duration,
+    /// Messages with more characters are skipped, zero allows messages of any length
+    pub max_message_len: usize,
+    /// Channel id of the account the bot posts with, its messages are never treated as commands
+    pub bot_channel_id: Option<String>,
+    /// How long a message is remembered to skip it if it's delivered again, zero
+    /// disables this and is the default
+    pub dedup_window: Duration,
+    /// Maximum number of remembered messages
+    pub dedup_capacity: usize,
+    /// Messages per minute sent to the chat, zero disables the limit
+    pub send_rate: f64,
+    /// Number of messages that may be sent in a burst
+    pub send_burst: u32,
+    /// Maximum number of messages or batches of messages waiting to be sent, further messages are dropped
+    pub send_queue: usize,
+    /// How often sending a message is retried after a transient error
+    pub send_retries: u32,
+    /// Delay before the first retry of a message, doubled after each retry
+    pub send_retry_delay: Duration,
+    /// How often each user may invoke a command within a window, e.g. `roll=100/1d`
+    pub quotas: Quotas,
+    /// Users at or above this level bypass cooldowns and rate limits, `None` if nobody does
+    pub bypass_level: Option<PermissionLevel>,
+}
+
+impl Default for ProcessorConfig {
+    fn default() -> Self {
+        ProcessorConfig {
+            reconnect_base: Duration::from_millis(100),
+            reconnect_max: Duration::from_secs(30),
+            default_cooldown: Duration::ZERO,
+            user_rate: 0.0,
+            user_burst: 5,
+            prefixes: vec!["!".to_string()],
+            conflict_policy: ConflictPolicy::Reject,
+            core_version_req: None,
+            user_cache_ttl: Duration::from_secs(60),
+            user_cache_size: 10_000,
+            user_retries: 1,
+            user_retry_delay: Duration::from_millis(100),
+            max_concurrency: 16,
+            overload_policy: OverloadPolicy::Wait,
+            breaker_threshold: 5,
+            breaker_cooldown: Duration::from_secs(60),
+            suggestions: false,
+            suggest_distance: 2,
+            help_command: Some("help".to_string()),
+            admin_commands: false,
+            state_file: None,
+            state_save_interval: Duration::from_secs(60),
+            invocation_event_capacity: 256,
+            command_timeout: None,
+            command_overload_policy: OverloadPolicy::Wait,
+            blocking_threads: 4,
+            unload_timeout: Duration::from_secs(5),
+            force_unload_timeout: Duration::from_secs(10),
+            max_message_len: 1000,
+            bot_channel_id: None,
+            // A user repeating a command is indistinguishable from a redelivery, so
+            // skipping duplicates is opt-in
+            dedup_window: Duration::ZERO,
+            dedup_capacity: 4096,
+            send_rate: 0.0,
+            send_burst: 3,
+            send_queue: 256,
+            send_retries: 3,
+            send_retry_delay: Duration::from_millis(500),
+            quotas: Quotas::default(),
+            bypass_level: None,
+        }
+    }
+}
+
+impl ProcessorConfig {
+    /// Builds the config from the `CS_*` environment variables
+    pub fn from_env() -> Self {
+        let default = ProcessorConfig::default();
+        ProcessorConfig {
+            // Without any delay a dropped subscription would be retried in a hot loop
+            reconnect_base: Duration::from_millis(env_or(
+                "CS_RECONNECT_BASE_MS",
+                default.reconnect_base.as_millis() as u64,
+            ).max(1)),
+            reconnect_max: Duration::from_millis(env_or(
+                "CS_RECONNECT_MAX_MS",
+                default.reconnect_max.as_millis() as u64,
+            )),
+            default_cooldown: Duration::from_secs(env_or(
+                "CS_COOLDOWN_SECS",
+                default.default_cooldown.as_secs(),
+            )),
+            user_rate: env_or("CS_USER_RATE", default.user_rate),
+            user_burst: env_or("CS_USER_BURST", default.user_burst),
+            prefixes: prefixes_from_env().unwrap_or(default.prefixes),
+            conflict_policy: env_or("CS_CONFLICT_POLICY", default.conflict_policy),
+            core_version_req: core_version_req_from_env(),
+            user_cache_ttl: Duration::from_secs(env_or(
+                "CS_USER_CACHE_TTL",
+                default.user_cache_ttl.as_secs(),
+            )),
+            user_cache_size: env_or("CS_USER_CACHE_SIZE", default.user_cache_size),
+            user_retries: env_or("CS_USER_RETRIES", default.user_retries),
+            user_retry_delay: Duration::from_millis(env_or(
+                "CS_USER_RETRY_MS",
+                default.user_retry_delay.as_millis() as u64,
+            )),
+            max_concurrency: env_or("CS_MAX_CONCURRENCY", default.max_concurrency).max(1),
+            overload_policy: env_or("CS_OVERLOAD_POLICY", default.overload_policy),
+            breaker_threshold: env_or("CS_BREAKER_THRESHOLD", default.breaker_threshold),
+            breaker_cooldown: Duration::from_secs(env_or(
+                "CS_BREAKER_COOLDOWN_SECS",
+                default.breaker_cooldown.as_secs(),
+            )),
+            suggestions: env::var_os("CS_SUGGESTIONS").is_some(),
+            suggest_distance: env_or("CS_SUGGEST_DISTANCE", default.suggest_distance),
+            help_command: help_command_from_env(default.help_command),
+            admin_commands: env::var_os("CS_ADMIN_COMMANDS").is_some(),
+            state_file: env::var_os("CS_STATE_FILE").map(PathBuf::from),
+            state_save_interval: Duration::from_secs(env_or(
+                "CS_STATE_SAVE_INTERVAL_SECS",
+                default.state_save_interval.as_secs(),
+            )),
+            invocation_event_capacity: env_or(
+                "CS_INVOCATION_EVENT_CAPACITY",
+                default.invocation_event_capacity,
+            )
+            .max(1),
+            command_timeout: Some(Duration::from_secs(env_or("CS_COMMAND_TIMEOUT_SECS", 0)))
+                .filter(|timeout| *timeout > Duration::ZERO),
+            command_overload_policy: env_or("CS_COMMAND_OVERLOAD_POLICY", default.command_overload_policy),
+            blocking_threads: env_or("CS_BLOCKING_THREADS", default.blocking_threads).max(1),
+            unload_timeout: Duration::from_secs(env_or(
+                "CS_UNLOAD_TIMEOUT_SECS",
+                default.unload_timeout.as_secs(),
+            )),
+            force_unload_timeout: Duration::from_secs(env_or(
+                "CS_FORCE_UNLOAD_TIMEOUT_SECS",
+                default.force_unload_timeout.as_secs(),
+            )),
+            max_message_len: env_or("CS_MAX_MESSAGE_LEN", default.max_message_len),
+            bot_channel_id: env::var("CS_BOT_CHANNEL_ID").ok().filter(|channel_id| !channel_id.is_empty()),
+            dedup_window: Duration::from_millis(env_or(
+                "CS_DEDUP_WINDOW_MS",
+                default.dedup_window.as_millis() as u64,
+            )),
+            dedup_capacity: env_or("CS_DEDUP_CAPACITY", default.dedup_capacity),
+            send_rate: env_or("CS_SEND_RATE", default.send_rate),
+            send_burst: env_or("CS_SEND_BURST", default.send_burst),
+            send_queue: env_or("CS_SEND_QUEUE", default.send_queue).max(1),
+            send_retries: env_or("CS_SEND_RETRIES", default.send_retries),
+            send_retry_delay: Duration::from_millis(env_or(
+                "CS_SEND_RETRY_DELAY_MS",
+                default.send_retry_delay.as_millis() as u64,
+            )),
+            quotas: env_or("CS_QUOTAS", default.quotas),
+            bypass_level: bypass_level_from_env(),
+        }
+    }
+
+    /// Whether a message exceeds `max_message_len`
+    pub fn is_too_long(&self, message: &str) -> bool {
+        // A string has at least as many bytes as characters, so short messages aren't counted
+        self.max_message_len > 0
+            && message.len() > self.max_message_len
+            && message.chars().count() > self.max_message_len
+    }
+
+    /// Returns the prefix a message starts with, preferring the longest one
+    /// if multiple prefixes match
+    pub fn match_prefix(&self, message: &str) -> Option<&str> {
+        self.prefixes
+            .iter()
+            .filter(|prefix| message.starts_with(prefix.as_str()))
+            .max_by_key(|prefix| prefix.len())
+            .map(String::as_str)
+    }
+}
+
+/// HTTP/2 keepalive of the gRPC server and the connections to the other services
+#[derive(Clone, Copy, Debug)]
+pub struct Keepalive {
+    /// How often pings are sent, `None` disables keepalive
+    pub interval: Option<Duration>,
+    /// How long to wait for the answer to a ping before closing the connection
+    pub timeout: Duration,
+}
+
+impl Keepalive {
+    /// Reads `CS_KEEPALIVE_INTERVAL_SECS`, zero disables keepalive, and `CS_KEEPALIVE_TIMEOUT_SECS`
+    pub fn from_env() -> Self {
+        Keepalive {
+            interval: Some(Duration::from_secs(env_or("CS_KEEPALIVE_INTERVAL_SECS", 30)))
+                .filter(|interval| *interval > Duration::ZERO),
+            timeout: Duration::from_secs(env_or("CS_KEEPALIVE_TIMEOUT_SECS", 20)),
+        }
+    }
+}
+
+/// Where the gRPC server listens
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    /// Path of a Unix domain socket
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddress::Tcp(address) => write!(f, "{}", address),
+            ListenAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Settings for running the whole service, see [`crate::run`]
+#[derive(Clone, Debug)]
+pub struct ServiceConfig {
+    /// Address of the YouTube service
+    pub youtube_address: String,
+    /// Address of the user service
+    pub user_address: String,
+    /// Address the gRPC server listens on
+    pub listen: ListenAddress,
+    /// Directories to load command libraries from, earlier directories take precedence
+    pub commands_directories: Vec<PathBuf>,
+    /// How often connecting to the other services is attempted
+    pub connect_attempts: u32,
+    /// Delay before the second connection attempt, doubled after each attempt up to 30 seconds
+    pub connect_delay: Duration,
+    /// Debounce of the hot reload watcher, `None` if hot reloading is disabled
+    pub hot_reload_debounce: Option<Duration>,
+    /// Address the metrics are served on, `None` if they aren't served
+    pub metrics_address: Option<SocketAddr>,
+    /// How long running commands get to finish once the service is shutting down
+    pub shutdown_timeout: Duration,
+    pub keepalive: Keepalive,
+    pub processor: ProcessorConfig,
+}
+
+impl ServiceConfig {
+    /// Builds the config from the command line, which falls back to the
+    /// environment, and the remaining `CS_*` environment variables
+    pub fn from_cli(cli: &Cli) -> Result<Self, Box<dyn std::error::Error>> {
+        let youtube_address = cli
+            .youtube_address
+            .clone()
+            .ok_or("--youtube-address or YTS_GRPC_ADDRESS must be set")?;
+        let user_address = cli
+            .user_address
+            .clone()
+            .ok_or("--user-address or US_GRPC_ADDRESS must be set")?;
+        let metrics_address = match env::var("CS_METRICS_ADDR") {
+            Ok(metrics_address) => Some(metrics_address.parse()?),
+            Err(_) => None,
+        };
+        let hot_reload_debounce = if env::var_os("CS_HOT_RELOAD").is_some() {
+            Some(Duration::from_millis(env_or("CS_HOT_RELOAD_DEBOUNCE_MS", 2000)))
+        } else {
+            None
+        };
+
+        Ok(ServiceConfig {
+            youtube_address,
+            user_address,
+            listen: cli
+                .uds
+                .clone()
+                .map_or(ListenAddress::Tcp(cli.listen), ListenAddress::Unix),
+            commands_directories: cli.commands_directories(),
+            // The other services might still be starting up during a deploy
+            connect_attempts: env_or("CS_CONNECT_ATTEMPTS", 10),
+            connect_delay: Duration::from_millis(env_or("CS_CONNECT_DELAY_MS", 500)),
+            hot_reload_debounce,
+            metrics_address,
+            shutdown_timeout: Duration::from_secs(env_or("CS_SHUTDOWN_TIMEOUT_SECS", 10)),
+            keepalive: Keepalive::from_env(),
+            processor: ProcessorConfig::from_env(),
+        })
+    }
+}
@@ -0,0 +1,72 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    time::{Duration, Instant},
+};
+use parking_lot::Mutex;
+
+#[derive(Default)]
+struct Seen {
+    /// Hashes of the seen messages, oldest first
+    order: VecDeque<(u64, Instant)>,
+    /// When each message was first seen within the window
+    first_seen: HashMap<u64, Instant>,
+}
+
+/// Chat messages seen within a short window, used to skip messages that are
+/// delivered more than once.
+///
+/// Messages are identified by a hash of their channel id and text. Entries are
+/// evicted once they're older than the window, or oldest first once the set is
+/// full.
+pub struct RecentMessages {
+    window: Duration,
+    capacity: usize,
+    seen: Mutex<Seen>,
+}
+
+impl RecentMessages {
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        RecentMessages {
+            window,
+            capacity,
+            seen: Mutex::new(Seen::default()),
+        }
+    }
+
+    /// Whether duplicates are detected at all
+    pub fn is_enabled(&self) -> bool {
+        self.window > Duration::ZERO && self.capacity > 0
+    }
+
+    /// Records a message, returns true if the same message was already seen within the window
+    pub fn is_duplicate(&self, channel_id: &str, message: &str) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        (channel_id, message).hash(&mut hasher);
+        let key = hasher.finish();
+
+        let now = Instant::now();
+        let mut seen = self.seen.lock();
+        while let Some(&(oldest, seen_at)) = seen.order.front() {
+            if now.duration_since(seen_at) < self.window && seen.order.len() < self.capacity {
+                break;
+            }
+            seen.order.pop_front();
+            // The message might have been seen again after this entry expired
+            if seen.first_seen.get(&oldest) == Some(&seen_at) {
+                seen.first_seen.remove(&oldest);
+            }
+        }
+
+        if seen.first_seen.contains_key(&key) {
+            return true;
+        }
+        seen.first_seen.insert(key, now);
+        seen.order.push_back((key, now));
+        false
+    }
+}
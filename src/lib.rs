@@ -0,0 +1,458 @@
+//! Runs commands from plugin libraries for ByersPlusPlus, see [`run`].
+
+use tonic::transport::{Certificate, Endpoint, Identity, Server, ServerTlsConfig};
+use ::log::{error, info, warn};
+use crate::{config::{Keepalive, ListenAddress, ProcessorConfig, ServiceConfig}, loader::CommandProcessor};
+use tokio::{net::TcpListener, sync::oneshot, task::JoinHandle};
+use tokio_stream::wrappers::TcpListenerStream;
+use tokio_util::sync::CancellationToken;
+use std::{env, future::Future, path::{Path, PathBuf}, sync::Arc, time::Duration};
+
+use commandservice::*;
+
+pub mod cli;
+pub mod config;
+pub mod loader;
+pub mod log;
+pub mod services;
+pub mod telemetry;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod admin;
+mod args;
+mod auth;
+mod breaker;
+mod dedup;
+mod inflight;
+mod metrics;
+mod outbound;
+mod permissions;
+mod quota;
+mod ratelimit;
+mod readiness;
+mod singleflight;
+mod state;
+mod stats;
+mod suggest;
+mod usercache;
+
+pub mod commandservice {
+    tonic::include_proto!("commandservice");
+
+    pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("commandservice_descriptor");
+}
+
+// Implement your proto here
+// https://github.com/hyperium/tonic/blob/master/examples/helloworld-tutorial.md
+// https://github.com/hyperium/tonic/blob/master/examples/routeguide-tutorial.md
+
+fn ensure_command_directory(commands_directory: &Path) {
+    if !commands_directory.exists() {
+        std::fs::create_dir_all(commands_directory).unwrap();
+    }
+}
+
+/// Returns every shared library in the commands directories, in the order of the directories
+fn library_files(commands_directories: &[PathBuf]) -> std::io::Result<Vec<PathBuf>> {
+    let mut library_files = Vec::new();
+    for commands_directory in commands_directories {
+        for entry in std::fs::read_dir(commands_directory)? {
+            let path = entry?.path();
+            if path.is_file() && loader::is_library(&path) {
+                library_files.push(path);
+            }
+        }
+    }
+    Ok(library_files)
+}
+
+fn load_commands(loader: &CommandProcessor, commands_directories: &[PathBuf]) {
+    // for each file in the commands directories, that is a shared library, load it
+    for path in library_files(commands_directories).unwrap() {
+        // Libraries are identified by their file name, earlier directories take precedence
+        let file_name = path.file_name().unwrap().to_str().unwrap();
+        if let Some(loaded_path) = loader.library_path(file_name) {
+            warn!(
+                "Library {} exists in multiple directories, using {} and skipping {}",
+                file_name,
+                loaded_path.display(),
+                path.display()
+            );
+            continue;
+        }
+
+        info!("Loading library: {}", path.display());
+        unsafe {
+            let load_result = loader.load(&path);
+            if load_result.is_err() {
+                error!("Error loading library: {}", load_result.err().unwrap());
+            }
+        }
+    }
+}
+
+/// Creates a processor that loads libraries without the other services.
+///
+/// The clients connect lazily, so nothing is dialed as long as no messages are processed.
+fn offline_processor() -> Result<CommandProcessor, Box<dyn std::error::Error>> {
+    let channel = Endpoint::from_static("http://[::1]:50051").connect_lazy()?;
+    Ok(CommandProcessor::new(
+        bpp_command_api::youtubeservice::you_tube_service_client::YouTubeServiceClient::new(channel.clone()),
+        bpp_command_api::userservice::user_service_client::UserServiceClient::new(channel),
+        ProcessorConfig::from_env(),
+    ))
+}
+
+/// Prints a table of the commands every library registers
+pub fn list(commands_directories: &[PathBuf]) -> Result<(), Box<dyn std::error::Error>> {
+    let processor = offline_processor()?;
+    load_commands(&processor, commands_directories);
+
+    let rows: Vec<[String; 3]> = processor
+        .commands()
+        .into_iter()
+        .map(|(library, command)| [command.name.clone(), command.aliases.join(", "), library])
+        .collect();
+    let header = ["COMMAND".to_string(), "ALIASES".to_string(), "LIBRARY".to_string()];
+    let mut widths = [0; 3];
+    for row in std::iter::once(&header).chain(&rows) {
+        for (width, column) in widths.iter_mut().zip(row) {
+            *width = (*width).max(column.len());
+        }
+    }
+
+    for row in std::iter::once(&header).chain(&rows) {
+        println!("{:<3$}  {:<4$}  {}", row[0], row[1], row[2], widths[0], widths[1]);
+    }
+    Ok(())
+}
+
+/// Loads and unloads every library, returns whether all of them succeeded
+pub async fn validate(commands_directories: &[PathBuf]) -> Result<bool, Box<dyn std::error::Error>> {
+    let processor = offline_processor()?;
+    let mut loaded = Vec::new();
+    let mut failures = 0;
+
+    for path in library_files(commands_directories)? {
+        let file_name = path.file_name().unwrap().to_str().unwrap().to_string();
+        match unsafe { processor.load(&path) } {
+            Ok(_) => {
+                println!("ok      {}", path.display());
+                loaded.push(file_name);
+            }
+            Err(loader::ProcessorError::AlreadyLoaded { .. }) => {
+                println!("skipped {} (shadowed by a library from an earlier directory)", path.display());
+            }
+            Err(err) => {
+                println!("failed  {}: {}", path.display(), err);
+                failures += 1;
+            }
+        }
+    }
+
+    for library_name in loaded {
+        let unload_result = processor.unload(&library_name).await;
+        if unload_result.is_err() {
+            println!("failed  {}: {}", library_name, unload_result.err().unwrap());
+            failures += 1;
+        }
+    }
+
+    if failures > 0 {
+        println!("{} libraries failed validation", failures);
+    }
+    Ok(failures == 0)
+}
+
+type CommandServiceServer = commandservice::command_service_server::CommandServiceServer<loader::CommandServiceServer>;
+
+/// Reports the command service as serving while it's able to run commands.
+///
+/// This is the readiness of the service. The overall health, queried with an
+/// empty service name, stays serving as long as the process is up and serves
+/// as the liveness.
+async fn report_health(mut health_reporter: tonic_health::server::HealthReporter, loader: Arc<CommandProcessor>) {
+    let mut readiness = loader.readiness();
+    loop {
+        let current = *readiness.borrow();
+        if current.is_ready() {
+            info!("Ready to process commands");
+            health_reporter.set_serving::<CommandServiceServer>().await;
+        } else {
+            warn!("Not ready to process commands: {}", current.failing().join(", "));
+            health_reporter.set_not_serving::<CommandServiceServer>().await;
+        }
+
+        if readiness.changed().await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Reads the TLS settings of the gRPC server from `CS_TLS_CERT` and `CS_TLS_KEY`.
+///
+/// Returns `None` if neither is set, in which case the server uses plaintext.
+/// If `CS_TLS_CLIENT_CA` is set as well, clients have to authenticate with a
+/// certificate signed by that CA.
+fn tls_config() -> Result<Option<ServerTlsConfig>, Box<dyn std::error::Error>> {
+    let cert_path = env::var("CS_TLS_CERT").ok();
+    let key_path = env::var("CS_TLS_KEY").ok();
+
+    let (cert_path, key_path) = match (cert_path, key_path) {
+        (None, None) if env::var_os("CS_TLS_CLIENT_CA").is_some() => {
+            return Err("CS_TLS_CLIENT_CA requires CS_TLS_CERT and CS_TLS_KEY to be set".into())
+        }
+        (None, None) => return Ok(None),
+        (Some(_), None) => return Err("CS_TLS_CERT is set, but CS_TLS_KEY is missing".into()),
+        (None, Some(_)) => return Err("CS_TLS_KEY is set, but CS_TLS_CERT is missing".into()),
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+    };
+
+    let cert = std::fs::read(&cert_path)
+        .map_err(|err| format!("Unable to read TLS certificate {}: {}", cert_path, err))?;
+    let key = std::fs::read(&key_path)
+        .map_err(|err| format!("Unable to read TLS key {}: {}", key_path, err))?;
+
+    let mut tls_config = ServerTlsConfig::new().identity(Identity::from_pem(cert, key));
+    if let Ok(client_ca_path) = env::var("CS_TLS_CLIENT_CA") {
+        let client_ca = std::fs::read(&client_ca_path)
+            .map_err(|err| format!("Unable to read TLS client CA {}: {}", client_ca_path, err))?;
+        info!("Requiring client certificates");
+        tls_config = tls_config.client_ca_root(Certificate::from_pem(client_ca));
+    }
+
+    Ok(Some(tls_config))
+}
+
+/// Builds the endpoint of another service with the configured keepalive
+fn endpoint(address: String, keepalive: Keepalive) -> Result<Endpoint, Box<dyn std::error::Error>> {
+    let mut endpoint = Endpoint::from_shared(address)?;
+    if let Some(interval) = keepalive.interval {
+        endpoint = endpoint
+            .http2_keep_alive_interval(interval)
+            .keep_alive_timeout(keepalive.timeout)
+            // The message stream can be quiet for a long time
+            .keep_alive_while_idle(true);
+    }
+    Ok(endpoint)
+}
+
+/// Upper bound for the delay between two attempts to connect to a service
+const MAX_CONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Calls `connect` until it succeeds, waiting twice as long after each failed
+/// attempt, but at most [`MAX_CONNECT_DELAY`]
+async fn connect_with_retry<T, F, Fut>(name: &str, attempts: u32, mut delay: Duration, connect: F) -> Result<T, tonic::transport::Error>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T, tonic::transport::Error>>,
+{
+    let mut attempt = 1;
+    loop {
+        info!("Connecting to {} (attempt {}/{})", name, attempt, attempts);
+        let connect_result = connect().await;
+        if connect_result.is_ok() || attempt >= attempts {
+            return connect_result;
+        }
+
+        warn!("Unable to connect to {}, retrying in {:?}: {}", name, delay, connect_result.err().unwrap());
+        tokio::time::sleep(delay).await;
+        delay = delay.saturating_mul(2).min(MAX_CONNECT_DELAY);
+        attempt += 1;
+    }
+}
+
+/// Removes a socket file left behind by a previous run, refusing to remove anything else
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let metadata = std::fs::symlink_metadata(path);
+    if metadata.is_err() {
+        // Nothing to clean up
+        return Ok(());
+    }
+    if !metadata.unwrap().file_type().is_socket() {
+        return Err(format!("{} exists and is not a socket", path.display()).into());
+    }
+
+    info!("Removing stale socket {}", path.display());
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+/// Binds the gRPC server to `address` and serves it in the background until
+/// `shutdown` is cancelled.
+///
+/// Returns the address the server is bound to, which is where to connect to
+/// if `address` asked for an ephemeral port, e.g. `127.0.0.1:0` in tests.
+async fn run_server(
+    loader: Arc<CommandProcessor>,
+    commands_directories: Vec<PathBuf>,
+    address: ListenAddress,
+    keepalive: Keepalive,
+    shutdown: CancellationToken,
+) -> Result<(ListenAddress, JoinHandle<Result<(), tonic::transport::Error>>), Box<dyn std::error::Error>> {
+    // The clients are connected at this point, so the service is ready as soon
+    // as the message stream is subscribed, if any libraries were loaded
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
+    tokio::spawn(report_health(health_reporter, loader.clone()));
+
+    let reflection_service = if env::var_os("CS_REFLECTION").is_some() {
+        info!("Enabling gRPC server reflection");
+        Some(
+            tonic_reflection::server::Builder::configure()
+                .register_encoded_file_descriptor_set(commandservice::FILE_DESCRIPTOR_SET)
+                .build()?,
+        )
+    } else {
+        None
+    };
+
+    let mut server_builder = Server::builder()
+        .http2_keepalive_interval(keepalive.interval)
+        .http2_keepalive_timeout(Some(keepalive.timeout));
+    if let Some(tls_config) = tls_config()? {
+        info!("Serving gRPC over TLS");
+        server_builder = server_builder.tls_config(tls_config)?;
+    }
+
+    let router = server_builder
+        .add_service(health_service)
+        .add_optional_service(reflection_service)
+        .add_service(CommandServiceServer::with_interceptor(loader::CommandServiceServer {
+            processor: loader,
+            commands_directories,
+        }, auth::client_identity_interceptor));
+    let shutdown = async move { shutdown.cancelled().await };
+
+    match address {
+        ListenAddress::Tcp(address) => {
+            let listener = TcpListener::bind(address).await?;
+            let bound_address = ListenAddress::Tcp(listener.local_addr()?);
+            info!("Serving gRPC on {}", bound_address);
+
+            let server = router.serve_with_incoming_shutdown(TcpListenerStream::new(listener), shutdown);
+            Ok((bound_address, tokio::spawn(server)))
+        }
+        #[cfg(unix)]
+        ListenAddress::Unix(path) => {
+            remove_stale_socket(&path)?;
+            let listener = tokio::net::UnixListener::bind(&path)?;
+            info!("Serving gRPC on unix:{}", path.display());
+
+            let server = router.serve_with_incoming_shutdown(tokio_stream::wrappers::UnixListenerStream::new(listener), shutdown);
+            let bound_address = ListenAddress::Unix(path.clone());
+            Ok((bound_address, tokio::spawn(async move {
+                let server_result = server.await;
+                // Don't leave the socket behind for the next run
+                let _ = std::fs::remove_file(&path);
+                server_result
+            })))
+        }
+        #[cfg(not(unix))]
+        ListenAddress::Unix(_) => Err("Unix domain sockets are only supported on unix".into()),
+    }
+}
+
+/// Connects to the other services, loads the libraries and processes messages
+/// and gRPC requests until `shutdown` resolves.
+///
+/// Commands that are still running when `shutdown` resolves get
+/// `config.shutdown_timeout` to finish. The address the gRPC server is bound to
+/// is sent to `bound` once it's listening, to find an ephemeral port.
+pub async fn run<F: Future<Output = ()>>(
+    config: ServiceConfig,
+    shutdown: F,
+    bound: Option<oneshot::Sender<ListenAddress>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let youtube_endpoint = endpoint(config.youtube_address, config.keepalive)?;
+    let user_endpoint = endpoint(config.user_address, config.keepalive)?;
+    let youtube_channel = connect_with_retry("youtubeservice", config.connect_attempts, config.connect_delay, || {
+        youtube_endpoint.connect()
+    }).await?;
+    let user_channel = connect_with_retry("userservice", config.connect_attempts, config.connect_delay, || {
+        user_endpoint.connect()
+    }).await?;
+    let youtube_client = bpp_command_api::youtubeservice::you_tube_service_client::YouTubeServiceClient::new(youtube_channel);
+    let user_client = bpp_command_api::userservice::user_service_client::UserServiceClient::new(user_channel);
+
+    info!("Loading commands");
+    let commands_directories = config.commands_directories;
+    let loader = CommandProcessor::new(youtube_client, user_client, config.processor);
+    let loader_arc = Arc::new(loader);
+    for commands_directory in &commands_directories {
+        ensure_command_directory(commands_directory);
+    }
+    loader::remove_staged_libraries();
+    load_commands(&loader_arc, &commands_directories);
+
+    if let Some(debounce) = config.hot_reload_debounce {
+        for commands_directory in &commands_directories {
+            info!("Watching {} for changes", commands_directory.display());
+            let watch_result = loader_arc.watch_directory(commands_directory, debounce);
+            if watch_result.is_err() {
+                error!("Error watching commands directory: {}", watch_result.err().unwrap());
+            }
+        }
+    }
+
+    let shutdown_token = CancellationToken::new();
+
+    let saving = loader_arc.clone();
+    let saving_shutdown = shutdown_token.clone();
+    tokio::spawn(async move { saving.save_state_periodically(saving_shutdown).await });
+
+    if let Some(metrics_address) = config.metrics_address {
+        info!("Serving metrics on {}", metrics_address);
+        let metrics_server = metrics::serve(loader_arc.metrics(), metrics_address, shutdown_token.clone());
+        tokio::spawn(async move {
+            let serve_result = metrics_server.await;
+            if serve_result.is_err() {
+                error!("Unable to serve metrics on {}: {}", metrics_address, serve_result.err().unwrap());
+            }
+        });
+    }
+
+    let (bound_address, server) = run_server(loader_arc.clone(), commands_directories, config.listen, config.keepalive, shutdown_token.clone()).await?;
+    if let Some(bound) = bound {
+        // Nobody might be waiting for it anymore, which is fine
+        let _ = bound.send(bound_address);
+    }
+
+    let fetch_shutdown = shutdown_token.clone();
+    let shutdown_timeout = config.shutdown_timeout;
+
+    let fetch_loader = loader_arc.clone();
+    let (_, _, _) = tokio::join!(
+        async move {
+            let server_result = server.await;
+            if let Ok(Err(err)) = server_result {
+                error!("gRPC server failed: {}", err);
+            }
+        },
+        async move {
+            let fetch = fetch_loader.fetch_messages(fetch_shutdown.clone());
+            tokio::pin!(fetch);
+            tokio::select! {
+                _ = &mut fetch => {}
+                _ = fetch_shutdown.cancelled() => {
+                    // Give commands that are currently running the chance to finish
+                    if tokio::time::timeout(shutdown_timeout, fetch).await.is_err() {
+                        warn!("Commands still running after {:?}, stopping anyway", shutdown_timeout);
+                    }
+                }
+            }
+        },
+        async move {
+            shutdown.await;
+            info!("Shutting down");
+            shutdown_token.cancel();
+        }
+    );
+
+    // Keeps the quota usage of the current windows across restarts
+    loader_arc.save_state().await;
+
+    Ok(())
+}
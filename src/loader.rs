@@ -1,36 +1,314 @@
 use async_trait::async_trait;
-use std::{ collections::HashMap, ffi::OsStr, path::PathBuf, sync::{Arc, Mutex}, time::Duration};
-use tonic::{Request, transport::Channel};
+use futures::{stream::FuturesUnordered, FutureExt, Stream, StreamExt};
+use std::{ any::Any, collections::{HashMap, HashSet}, env::consts::DLL_EXTENSION, ffi::OsStr, panic::AssertUnwindSafe, path::{Path, PathBuf}, pin::Pin, sync::{atomic::Ordering, Arc, Weak}, time::{Duration, Instant}};
 
-use bpp_command_api::{structs::ServiceDirectory, youtubeservice::you_tube_service_client::YouTubeServiceClient};
-use bpp_command_api::{userservice::user_service_client::UserServiceClient};
+use bpp_command_api::{structs::{LibraryContext, PermissionLevel, ServiceDirectory}, youtubeservice::you_tube_service_client::YouTubeServiceClient};
+use bpp_command_api::{userservice::{user_service_client::UserServiceClient, BppUser}};
 use bpp_command_api::{
     structs::Message,
-    traits::Command,
+    traits::{Command, Task, YouTubeSendable},
     CommandDeclaration, CommandError,
 };
 use libloading::Library;
+use crate::{admin::AdminCommand, args, breaker::CircuitBreaker, config::{ConflictPolicy, OverloadPolicy, ProcessorConfig}, dedup::RecentMessages, inflight::{InFlight, InFlightGuard}, log::CORRELATION_ID, metrics::Metrics, outbound::Outbound, permissions, quota::QuotaTracker, ratelimit::RateLimiter, readiness::{Readiness, ReadinessState}, services::{ChatMessage, Sendable, UserLookup, UserService}, singleflight::SingleFlight, state::PersistedState, stats::StatsRegistry, suggest, usercache::UserCache};
 use log::{debug, error, info, warn};
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use parking_lot::{Mutex, RwLock};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use tokio::{runtime::Handle, sync::{broadcast, mpsc, watch, OwnedSemaphorePermit, Semaphore}, task::JoinHandle, time::MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 
 type Void = Result<(), Box<dyn std::error::Error>>;
 
 custom_error::custom_error! { pub ProcessorError
     CommandNotFound { command: String } = "Command {} not found",
     CommandExecutionFailed { command: String, library: String, message: String } = "Command {} (from library {}) errored with the following message: {}",
+    CommandPanicked { command: String, library: String } = "Command {} (from library {}) panicked",
+    Timeout { command: String, timeout_secs: u64 } = "Command {} did not finish within {} seconds",
+    Cooldown { command: String, remaining_secs: u64 } = "Command {} is on cooldown for another {} seconds",
+    RateLimited { channel_id: String, reason: String } = "User {} {}",
+    PermissionDenied { command: String } = "Insufficient permissions to run command {}",
+    CircuitOpen { command: String, remaining_secs: u64 } = "Command {} is disabled after failing repeatedly, it will be enabled again in {} seconds",
+    Disabled { command: String } = "Command {} is disabled",
+    CommandConflict { command: String, existing_library: String, new_library: String } = "Command {} is already registered by library {}, refusing to load {}",
     LoadError { library_name: String, message: String } = "Unable to load {}: {}",
+    LoadHookFailed { library_name: String, message: String } = "on_load of library {} failed: {}",
     LibraryRustCVersionMismatch { library_name: String, rustc_version: String, actual_rustc_version: String } = "Library {} has a different rustc version than this core.\n\tExpected: {}\n\tActual: {}",
-    LibraryCoreVersionMismatch { library_name: String, core_version: String, actual_core_version: String } = "Library {} has a different core version than this core.\n\tExpected: {}\n\tActual: {}"
+    LibraryCoreVersionMismatch { library_name: String, core_version: String, actual_core_version: String } = "Library {} has a different core version than this core.\n\tExpected: {}\n\tActual: {}",
+    WatchError { path: String, message: String } = "Unable to watch {}: {}",
+    LibraryNotFound { library_name: String } = "Library {} is not loaded",
+    LibraryInUse { library_name: String } = "Library {} is still in use and could not be unloaded",
+    InvalidLibraryPath { path: String, message: String } = "Refusing to load {}: {}",
+    AlreadyLoaded { library_name: String } = "Library {} is already loaded"
 }
 
+impl ProcessorError {
+    /// Returns the name of the variant, used to label metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ProcessorError::CommandNotFound { .. } => "command_not_found",
+            ProcessorError::CommandExecutionFailed { .. } => "command_execution_failed",
+            ProcessorError::CommandPanicked { .. } => "command_panicked",
+            ProcessorError::Timeout { .. } => "timeout",
+            ProcessorError::Cooldown { .. } => "cooldown",
+            ProcessorError::RateLimited { .. } => "rate_limited",
+            ProcessorError::PermissionDenied { .. } => "permission_denied",
+            ProcessorError::CircuitOpen { .. } => "circuit_open",
+            ProcessorError::Disabled { .. } => "disabled",
+            ProcessorError::CommandConflict { .. } => "command_conflict",
+            ProcessorError::LoadError { .. } => "load_error",
+            ProcessorError::LoadHookFailed { .. } => "load_hook_failed",
+            ProcessorError::LibraryRustCVersionMismatch { .. } => "library_rustc_version_mismatch",
+            ProcessorError::LibraryCoreVersionMismatch { .. } => "library_core_version_mismatch",
+            ProcessorError::WatchError { .. } => "watch_error",
+            ProcessorError::LibraryNotFound { .. } => "library_not_found",
+            ProcessorError::LibraryInUse { .. } => "library_in_use",
+            ProcessorError::InvalidLibraryPath { .. } => "invalid_library_path",
+            ProcessorError::AlreadyLoaded { .. } => "already_loaded",
+        }
+    }
+}
+
+/// Logs message tasks that didn't finish, commands panicking inside are already caught
+fn log_task_result(result: Result<(), tokio::task::JoinError>) {
+    if result.is_err() {
+        error!("Message task failed: {}", result.err().unwrap());
+    }
+}
+
+/// Whether a user lookup failed because the userservice couldn't be reached
+fn is_unreachable(status: &tonic::Status) -> bool {
+    matches!(status.code(), tonic::Code::Unavailable | tonic::Code::DeadlineExceeded)
+}
+
+/// Separates the library from the command name in namespaced invocations like `!mylib::stats`
+const NAMESPACE_SEPARATOR: &str = "::";
+
+/// Whether a namespace in a command invocation refers to a library.
+///
+/// Libraries are named after their file, so `mylib` matches `libmylib.so`,
+/// `mylib.so` and `mylib.dll` as well as the full file name, ignoring case.
+fn library_matches(library_name: &str, namespace: &str) -> bool {
+    let library_name = library_name.to_lowercase();
+    let stem = Path::new(&library_name)
+        .file_stem()
+        .and_then(OsStr::to_str)
+        .unwrap_or(&library_name);
+    library_name == namespace || stem == namespace || stem.strip_prefix("lib") == Some(namespace)
+}
+
+/// Returns whether the path points to a shared library for the current platform
+pub fn is_library<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref()
+        .extension()
+        .map_or(false, |extension| extension == DLL_EXTENSION)
+}
+
+/// Whether two paths point to the same file, e.g. a relative path a library
+/// was loaded through and the absolute path the watcher reports
+fn same_file(a: &Path, b: &Path) -> bool {
+    if a == b {
+        return true;
+    }
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Opens this process like a library, it stands in for the library of commands
+/// that are built into the process
+fn this_process() -> Result<Library, libloading::Error> {
+    #[cfg(unix)]
+    let library = Ok(libloading::os::unix::Library::this());
+    #[cfg(windows)]
+    let library = libloading::os::windows::Library::this();
+    library.map(Library::from)
+}
+
+/// Computes the SHA-256 checksum of a library file
+fn checksum<P: AsRef<Path>>(path: P, file_name: &str) -> Result<Vec<u8>, ProcessorError> {
+    let contents = std::fs::read(path);
+    if contents.is_err() {
+        return Err(ProcessorError::LoadError {
+            library_name: file_name.to_string(),
+            message: contents.err().unwrap().to_string(),
+        });
+    }
+
+    Ok(Sha256::digest(&contents.unwrap()).to_vec())
+}
+
+/// Returns the path of the configuration file of a library, `<library>.json` next to it
+pub fn config_path<P: AsRef<Path>>(library_path: P) -> PathBuf {
+    library_path.as_ref().with_extension("json")
+}
+
+/// Reads the configuration file of a library.
+///
+/// A library without a configuration file gets `None`. So does a library whose
+/// file can't be read or isn't valid JSON, the library is still loaded, but the
+/// error is logged.
+fn read_config(library_path: &Path) -> Option<serde_json::Value> {
+    let path = config_path(library_path);
+    let contents = std::fs::read_to_string(&path);
+    if contents.is_err() {
+        let err = contents.err().unwrap();
+        if err.kind() != std::io::ErrorKind::NotFound {
+            error!("Unable to read the configuration file {}: {}", path.display(), err);
+        }
+        return None;
+    }
+
+    let config = serde_json::from_str(&contents.unwrap());
+    if config.is_err() {
+        error!(
+            "The configuration file {} is not valid JSON, loading the library without it: {}",
+            path.display(),
+            config.err().unwrap()
+        );
+        return None;
+    }
+    Some(config.unwrap())
+}
+
+/// Copies a library to a unique file in the temp directory.
+///
+/// The dynamic loader caches libraries by path, so a rebuilt library has to be
+/// loaded from a different path while the old build is still open.
+fn stage_library(path: &Path, file_name: &str) -> Result<PathBuf, ProcessorError> {
+    let mut staged = std::env::temp_dir();
+    staged.push(format!("{}.{}.{}", file_name, rand::random::<u32>(), DLL_EXTENSION));
+
+    let copy_result = std::fs::copy(path, &staged);
+    if copy_result.is_err() {
+        return Err(ProcessorError::LoadError {
+            library_name: file_name.to_string(),
+            message: copy_result.err().unwrap().to_string(),
+        });
+    }
+
+    Ok(staged)
+}
+
+/// Whether a file name is one of a staged copy like `mylib.dll.1234.dll`
+fn is_staged_copy(file_name: &str) -> bool {
+    let extension = format!(".{}", DLL_EXTENSION);
+    let staged = file_name
+        .strip_suffix(extension.as_str())
+        .and_then(|rest| rest.rsplit_once('.'));
+    matches!(staged, Some((library, random))
+        if library.ends_with(extension.as_str()) && !random.is_empty() && random.bytes().all(|byte| byte.is_ascii_digit()))
+}
+
+/// Removes the staged copies that earlier runs left in the temp directory.
+///
+/// Windows doesn't allow deleting an open library, so the copies reloads
+/// opened stay behind until the next start.
+pub fn remove_staged_libraries() {
+    let entries = std::fs::read_dir(std::env::temp_dir());
+    if entries.is_err() {
+        warn!("Unable to look for stale staged libraries: {}", entries.err().unwrap());
+        return;
+    }
+    for entry in entries.unwrap().flatten() {
+        let path = entry.path();
+        if !path.file_name().and_then(OsStr::to_str).map_or(false, is_staged_copy) {
+            continue;
+        }
+        let remove_result = std::fs::remove_file(&path);
+        if remove_result.is_err() {
+            // Most likely opened by another instance that is still running
+            debug!("Unable to remove stale staged library {}: {}", path.display(), remove_result.err().unwrap());
+        }
+    }
+}
+
+/// Describes what a library registered under a name, e.g. `alias st of command stats`
+fn describe_registration(name: &str, command: &CommandProxy) -> String {
+    if command.is_alias {
+        format!("alias {} of command {}", name, command.name)
+    } else {
+        format!("command {}", name)
+    }
+}
+
+/// Removes a name or alias from the commands of a library.
+///
+/// A skipped alias is dropped from the aliases of its command, a skipped
+/// command takes its aliases with it.
+fn skip_registration(registrar: &mut CommandRegistrar, name: &str) {
+    let skipped = registrar.commands.remove(name);
+    if skipped.is_none() {
+        return;
+    }
+    let skipped = skipped.unwrap();
+    if skipped.is_alias {
+        if let Some(command) = registrar.commands.get_mut(&skipped.name.to_lowercase()) {
+            command.aliases.retain(|alias| alias.to_lowercase() != name);
+        }
+    } else {
+        registrar
+            .commands
+            .retain(|_, command| !(command.is_alias && command.name == skipped.name));
+    }
+}
+
+/// Description used for commands that don't provide their own
+const DEFAULT_DESCRIPTION: &str = "A command for ByersPlusPlus";
+
 #[derive(Clone)]
 pub struct CommandProxy {
     pub command: Box<dyn Command>,
     _lib: Arc<Library>,
     _lib_name: String,
+    /// The primary name of the command, also set on alias entries
+    pub name: String,
     pub aliases: Vec<String>,
+    pub description: Option<String>,
+    /// Cooldown declared by the command, overriding the default cooldown
+    pub cooldown: Option<Duration>,
+    /// Minimum permission level a user needs to run the command
+    pub permission_level: PermissionLevel,
+    /// Whether the command needs the user data from the userservice to run
+    pub requires_user: bool,
+    /// Shared by the command and its aliases
+    pub breaker: Arc<CircuitBreaker>,
+    /// Shared by all commands of the library
+    in_flight: Arc<InFlight>,
+    /// Whether the command runs on the blocking thread pool through `execute_blocking`
+    pub blocking: bool,
+    /// Limits how many executions of the command may run at once, `None` if unlimited
+    pub concurrency: Option<Arc<Semaphore>>,
+    pub max_concurrency: Option<usize>,
+    /// Pattern that runs the command on messages without a prefix, compiled when the command is registered
+    pub trigger: Option<Arc<Regex>>,
+    /// Versions the library of the command declared it was built with
+    pub rustc_version: String,
+    pub core_version: String,
     pub is_alias: bool,
 }
 
+impl CommandProxy {
+    /// Builds the gRPC representation of this command
+    fn to_proto(&self, library: &str, enabled: bool) -> crate::commandservice::Command {
+        crate::commandservice::Command {
+            name: self.name.clone(),
+            aliases: self.aliases.clone(),
+            description: self
+                .description
+                .clone()
+                .unwrap_or_else(|| DEFAULT_DESCRIPTION.to_string()),
+            library: library.to_string(),
+            rustc_version: self.rustc_version.clone(),
+            core_version: self.core_version.clone(),
+            enabled,
+        }
+    }
+}
+
 #[async_trait]
 impl
     Command for CommandProxy
@@ -42,251 +320,2057 @@ impl
     ) -> Result<(), CommandError> {
         self.command.execute(message, service_directory).await
     }
+
+    async fn execute_with_reply(
+        &self,
+        message: Message,
+        service_directory: &mut ServiceDirectory,
+    ) -> Result<Option<String>, CommandError> {
+        self.command.execute_with_reply(message, service_directory).await
+    }
+
+    async fn execute_cancellable(
+        &self,
+        message: Message,
+        service_directory: &mut ServiceDirectory,
+        cancellation: CancellationToken,
+    ) -> Result<Option<String>, CommandError> {
+        self.command.execute_cancellable(message, service_directory, cancellation).await
+    }
+}
+
+/// Work a library wants to run on a timer instead of in response to a message
+struct PeriodicTask {
+    name: String,
+    interval: Duration,
+    task: Box<dyn Task>,
 }
 
 struct CommandRegistrar {
     commands: HashMap<String, CommandProxy>,
+    tasks: Vec<PeriodicTask>,
+    /// Names or aliases the library registered more than once, with the
+    /// primary name of the command that registered them first
+    collisions: Vec<(String, String)>,
+    /// Number of commands of the library that are currently executing
+    in_flight: Arc<InFlight>,
     lib: Arc<Library>,
     library_name: String,
+    library_path: PathBuf,
+    /// Versions the library declared it was built with
+    rustc_version: String,
+    core_version: String,
+    /// SHA-256 of the library file that was loaded
+    checksum: Vec<u8>,
+    /// Called right before the library is closed
+    on_unload: Option<fn(&LibraryContext)>,
+    /// Contents of the configuration file of the library
+    config: Option<serde_json::Value>,
 }
 
 impl CommandRegistrar {
-    fn new(lib: Arc<Library>, library_name: String) -> Self {
+    /// Creates a registrar for commands built together with this service,
+    /// libraries override the versions and hooks with [`Self::with_declaration`]
+    fn new(lib: Arc<Library>, library_name: String, library_path: PathBuf, checksum: Vec<u8>, config: Option<serde_json::Value>) -> Self {
         CommandRegistrar {
             commands: HashMap::new(),
+            tasks: Vec::new(),
+            collisions: Vec::new(),
+            in_flight: Arc::new(InFlight::default()),
             lib,
             library_name,
+            library_path,
+            rustc_version: bpp_command_api::RUSTC_VERSION.to_string(),
+            core_version: bpp_command_api::CORE_VERSION.to_string(),
+            checksum,
+            on_unload: None,
+            config,
+        }
+    }
+
+    /// Takes the versions and the unload hook from the declaration of a library
+    fn with_declaration(mut self, decl: &CommandDeclaration) -> Self {
+        self.rustc_version = decl.rustc_version.to_string();
+        self.core_version = decl.core_version.to_string();
+        self.on_unload = decl.on_unload;
+        self
+    }
+
+    fn context(&self) -> LibraryContext {
+        LibraryContext {
+            library_name: self.library_name.clone(),
+            library_path: self.library_path.clone(),
+            config: self.config.clone(),
+        }
+    }
+
+    /// Lets the library tear down whatever it set up in `on_load`.
+    ///
+    /// The library is closed afterwards either way, so failures are only logged.
+    fn run_unload_hook(&self) {
+        if let Some(on_unload) = self.on_unload {
+            let context = self.context();
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| on_unload(&context)));
+            if result.is_err() {
+                error!("on_unload of library {} panicked", self.library_name);
+            }
         }
     }
 }
 
 impl bpp_command_api::traits::CommandRegistrar for CommandRegistrar {
+    fn config(&self) -> Option<&serde_json::Value> {
+        self.config.as_ref()
+    }
+
     fn register_command(
         &mut self,
         name: &str,
         aliases: &[&str],
         command: Box<dyn Command>,
     ) {
+        let description = command.description();
+        let cooldown = command.cooldown();
+        let permission_level = command.permission_level();
+        let requires_user = command.requires_user();
+        let blocking = command.is_blocking();
+        let max_concurrency = command.max_concurrency().filter(|max_concurrency| *max_concurrency > 0);
+        let trigger = command.trigger_pattern().and_then(|pattern| {
+            let regex = Regex::new(&pattern);
+            if regex.is_err() {
+                warn!(
+                    "Trigger pattern of command {} of library {} is invalid, ignoring it: {}",
+                    name, self.library_name, regex.err().unwrap()
+                );
+                return None;
+            }
+            Some(Arc::new(regex.unwrap()))
+        });
+
+        // The first command to register a name keeps it
+        if let Some(existing) = self.commands.get(&name.to_lowercase()) {
+            self.collisions.push((name.to_string(), existing.name.clone()));
+            return;
+        }
+        // Names are matched case-insensitively, so aliases that only differ in case are duplicates too
+        let mut unique_aliases: Vec<String> = Vec::new();
+        for alias in aliases {
+            let lowercase = alias.to_lowercase();
+            if lowercase == name.to_lowercase() || unique_aliases.iter().any(|unique| unique.to_lowercase() == lowercase) {
+                continue;
+            }
+            if let Some(existing) = self.commands.get(&lowercase) {
+                self.collisions.push((alias.to_string(), existing.name.clone()));
+                continue;
+            }
+            unique_aliases.push(alias.to_string());
+        }
         let proxy = CommandProxy {
             command,
             _lib: Arc::clone(&self.lib),
             _lib_name: self.library_name.clone(),
-            aliases: aliases.iter().map(|a| a.to_string()).collect(),
+            name: name.to_string(),
+            aliases: unique_aliases,
+            description,
+            cooldown,
+            permission_level,
+            requires_user,
+            breaker: Arc::new(CircuitBreaker::default()),
+            in_flight: self.in_flight.clone(),
+            blocking,
+            concurrency: max_concurrency.map(|max_concurrency| Arc::new(Semaphore::new(max_concurrency))),
+            max_concurrency,
+            trigger,
+            rustc_version: self.rustc_version.clone(),
+            core_version: self.core_version.clone(),
             is_alias: false,
         };
 
-        for alias in aliases {
+        for alias in &proxy.aliases {
             let mut alias_proxy = proxy.clone();
             alias_proxy.is_alias = true;
             alias_proxy.aliases.clear();
-            self.commands.insert(alias.to_string(), alias_proxy);
+            self.commands.insert(alias.to_lowercase(), alias_proxy);
+        }
+        self.commands.insert(name.to_lowercase(), proxy);
+    }
+
+    fn register_task(
+        &mut self,
+        name: &str,
+        interval: Duration,
+        task: Box<dyn Task>,
+    ) {
+        if interval == Duration::ZERO {
+            warn!("Task {} of library {} has no interval, skipping it", name, self.library_name);
+            return;
+        }
+        self.tasks.push(PeriodicTask {
+            name: name.to_string(),
+            interval,
+            task,
+        });
+    }
+}
+
+/// Runs a task of a library every `interval` until `shutdown` is cancelled.
+///
+/// Only a weak reference to the registrar is kept between runs, so an idle task
+/// doesn't keep its library from being closed. While the task runs, the
+/// registrar is upgraded, which keeps the library loaded until the run finishes.
+async fn run_task<T: Sendable, U: UserService>(
+    registrar: Weak<CommandRegistrar>,
+    index: usize,
+    interval: Duration,
+    mut sender: T,
+    mut user_client: U,
+    shutdown: CancellationToken,
+) {
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick completes immediately, tasks first run after one interval
+    ticks.tick().await;
+
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => return,
+            _ = ticks.tick() => {}
+        }
+        // The library is gone once the registrar can't be upgraded anymore
+        let registrar = match registrar.upgrade() {
+            Some(registrar) => registrar,
+            None => return,
+        };
+        let task = &registrar.tasks[index];
+
+        let mut service_directory = ServiceDirectory {
+            userservice_client: &mut user_client,
+            youtubeservice_client: &mut sender,
+        };
+        // A panicking task must not take down the loop
+        let execution = AssertUnwindSafe(task.task.run(&mut service_directory)).catch_unwind();
+        let result = tokio::select! {
+            _ = shutdown.cancelled() => return,
+            result = execution => result,
+        };
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => error!(
+                task = task.name.as_str(), library = registrar.library_name.as_str();
+                "Task failed: {:?}", err
+            ),
+            Err(_) => error!(task = task.name.as_str(), library = registrar.library_name.as_str(); "Task panicked"),
+        }
+    }
+}
+
+/// How often `force_unload` checks whether a library is still in use
+const FORCE_UNLOAD_RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Number of library events kept for subscribers that fall behind
+const LIBRARY_EVENT_CAPACITY: usize = 64;
+
+/// Change to the set of loaded libraries
+#[derive(Clone, Debug)]
+pub enum LibraryEvent {
+    Loaded { library_name: String },
+    Reloaded { library_name: String },
+    Unloaded { library_name: String },
+    LoadFailed { library_name: String, message: String },
+}
+
+impl From<LibraryEvent> for crate::commandservice::LibraryEvent {
+    fn from(event: LibraryEvent) -> Self {
+        use crate::commandservice::library_event::Kind;
+
+        let (kind, library_name, message) = match event {
+            LibraryEvent::Loaded { library_name } => (Kind::Loaded, library_name, String::new()),
+            LibraryEvent::Reloaded { library_name } => (Kind::Reloaded, library_name, String::new()),
+            LibraryEvent::Unloaded { library_name } => (Kind::Unloaded, library_name, String::new()),
+            LibraryEvent::LoadFailed { library_name, message } => (Kind::LoadFailed, library_name, message),
+        };
+        crate::commandservice::LibraryEvent {
+            kind: kind as i32,
+            library_name,
+            message,
+        }
+    }
+}
+
+/// Execution of a command
+#[derive(Clone, Debug)]
+pub struct InvocationEvent {
+    /// Primary name of the command
+    pub command: String,
+    pub library: String,
+    pub channel_id: String,
+    /// `success`, or the kind of the [`ProcessorError`] the invocation failed with
+    pub result: &'static str,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+impl From<InvocationEvent> for crate::commandservice::InvocationEvent {
+    fn from(event: InvocationEvent) -> Self {
+        crate::commandservice::InvocationEvent {
+            command: event.command,
+            library: event.library,
+            channel_id: event.channel_id,
+            result: event.result.to_string(),
+            timestamp: Some(prost_types::Timestamp {
+                seconds: event.timestamp.timestamp(),
+                nanos: event.timestamp.timestamp_subsec_nanos() as i32,
+            }),
         }
-        self.commands.insert(name.to_string(), proxy);
     }
 }
 
-type YouTubeClient = Arc<Mutex<YouTubeServiceClient<tonic::transport::Channel>>>;
-type UserClient = Arc<Mutex<UserServiceClient<tonic::transport::Channel>>>;
+/// How a chat message invokes a command
+enum Trigger {
+    /// The message starts with this prefix, followed by the command name
+    Prefix(String),
+    /// The message matches the trigger pattern of this command, the arguments are
+    /// the capture groups of the pattern
+    Pattern { command: String, arguments: Vec<String> },
+}
+
+/// A message waiting for the previous messages of its user to be processed
+struct QueuedMessage {
+    message: ChatMessage,
+    correlation_id: String,
+    trigger: Trigger,
+    /// Counts the message against the concurrency limit until it's processed
+    _permit: OwnedSemaphorePermit,
+}
+
+// tonic clients are cheap to clone and share the underlying channel, so every
+// user of a client gets its own clone instead of locking a shared one
+type YouTubeClient = YouTubeServiceClient<tonic::transport::Channel>;
+type UserClient = UserServiceClient<tonic::transport::Channel>;
 
-pub struct CommandProcessor {
-    libraries: Arc<Mutex<HashMap<String, Arc<CommandRegistrar>>>>,
-    // youtube_sender: Arc<Mutex<YouTubeServiceClient<tonic::transport::Channel>>>,
-    youtube_sender: YouTubeClient,
-    userservice_client: UserClient,
+/// Dispatches chat messages of a [`Sendable`] platform to the commands of the
+/// loaded libraries, looking up their users through a [`UserService`]
+pub struct CommandProcessor<T: Sendable = YouTubeClient, U: UserService = UserClient> {
+    libraries: Arc<RwLock<HashMap<String, Arc<CommandRegistrar>>>>,
+    /// All names and aliases of every loaded library, rebuilt whenever the
+    /// libraries change so lookups don't have to go through every library
+    index: RwLock<HashMap<String, CommandProxy>>,
+    /// Primary names and trigger patterns of the commands that declare one,
+    /// sorted by name and rebuilt together with the index
+    triggers: RwLock<Vec<(String, Arc<Regex>)>>,
+    /// Sends through a rate limited queue, commands and tasks get clones of it
+    sender: Outbound<T>,
+    userservice_client: U,
+    config: ProcessorConfig,
+    /// Last successful invocation of each command, keyed by lowercase primary name
+    cooldowns: Mutex<HashMap<String, Instant>>,
+    /// Cooldowns set by an operator, keyed by lowercase primary name, taking
+    /// precedence over the cooldown declared by the command
+    cooldown_overrides: RwLock<HashMap<String, Duration>>,
+    /// Rate limiter keyed by channel id, `None` if rate limiting is disabled
+    user_limiter: Option<RateLimiter<String>>,
+    /// Invocations of each user of the commands with a quota
+    quotas: QuotaTracker,
+    /// Whether libraries are loaded and the other services are reachable
+    readiness: ReadinessState,
+    metrics: Arc<Metrics>,
+    stats: StatsRegistry,
+    started_at: Instant,
+    users: UserCache,
+    /// User lookups that are waiting for the userservice, keyed by channel id
+    user_lookups: SingleFlight<Result<BppUser, (tonic::Code, String)>>,
+    /// Command messages seen recently, to skip messages that are delivered twice
+    recent_messages: RecentMessages,
+    library_events: broadcast::Sender<LibraryEvent>,
+    invocation_events: broadcast::Sender<InvocationEvent>,
+    /// Lowercase primary names of the commands an operator disabled
+    disabled: RwLock<HashSet<String>>,
+    /// Held while the state file is written, so saves don't overlap
+    saving: tokio::sync::Mutex<()>,
+    /// Permits for processing messages, bounding the number of running commands
+    concurrency: Arc<Semaphore>,
+    /// Permits for running blocking commands
+    blocking_permits: Arc<Semaphore>,
+    /// Messages waiting to be processed, keyed by the channel id of their user
+    user_queues: Mutex<HashMap<String, mpsc::UnboundedSender<QueuedMessage>>>,
+    /// Cancelled once a library is unloaded, stopping its tasks and asking its
+    /// running commands to stop, keyed by library name
+    library_shutdowns: Mutex<HashMap<String, CancellationToken>>,
+    /// Every library this processor opened, to tell whether unloaded libraries
+    /// were actually closed
+    opened: Mutex<Vec<(String, Weak<Library>)>>,
+    /// Parent of the library tokens, cancelled once the service shuts down
+    shutdown: CancellationToken,
+    /// Libraries can be loaded from outside of the runtime, e.g. by the
+    /// directory watcher, so tasks are spawned through a handle
+    runtime: Handle,
 }
 
-impl CommandProcessor {
-    //pub fn new(youtube_sender: Arc<Mutex<YouTubeServiceClient<tonic::transport::Channel>>>) -> Self {
+impl<T: Sendable, U: UserService> CommandProcessor<T, U> {
+    /// Creates a processor without any libraries.
+    ///
+    /// Must be called from within a tokio runtime, which the periodic tasks of
+    /// the libraries are spawned on.
     pub fn new(
-        youtube_sender: YouTubeServiceClient<tonic::transport::Channel>,
-        userservice_client: UserServiceClient<tonic::transport::Channel>,
+        sender: T,
+        userservice_client: U,
+        config: ProcessorConfig,
     ) -> Self {
+        // The clients are connected before the processor is created
+        let readiness = ReadinessState::new(Readiness {
+            userservice_reachable: true,
+            ..Default::default()
+        });
+        let users = UserCache::new(config.user_cache_ttl, config.user_cache_size);
+        let recent_messages = RecentMessages::new(config.dedup_window, config.dedup_capacity);
+        let concurrency = Arc::new(Semaphore::new(config.max_concurrency));
+        let blocking_permits = Arc::new(Semaphore::new(config.blocking_threads));
+        let (library_events, _) = broadcast::channel(LIBRARY_EVENT_CAPACITY);
+        let (invocation_events, _) = broadcast::channel(config.invocation_event_capacity);
+        let metrics = Arc::new(Metrics::new());
+        let runtime = Handle::current();
+        let sender = Outbound::new(sender, &config, metrics.clone(), &runtime);
+        let state = config
+            .state_file
+            .as_deref()
+            .map(PersistedState::load)
+            .unwrap_or_default();
+        let disabled: HashSet<String> = state.disabled_commands.into_iter().collect();
+        let quotas = QuotaTracker::new(config.quotas.clone(), state.quota_usage);
+        let user_limiter = if config.user_rate > 0.0 {
+            Some(RateLimiter::new(config.user_rate, config.user_burst))
+        } else {
+            None
+        };
+
         CommandProcessor {
-            libraries: Arc::new(Mutex::new(HashMap::new())),
-            youtube_sender: Arc::new(Mutex::new(youtube_sender)),
-            userservice_client: Arc::new(Mutex::new(userservice_client))
+            libraries: Arc::new(RwLock::new(HashMap::new())),
+            index: RwLock::new(HashMap::new()),
+            triggers: RwLock::new(Vec::new()),
+            sender,
+            userservice_client,
+            config,
+            cooldowns: Mutex::new(HashMap::new()),
+            cooldown_overrides: RwLock::new(HashMap::new()),
+            user_limiter,
+            quotas,
+            readiness,
+            metrics,
+            stats: StatsRegistry::default(),
+            started_at: Instant::now(),
+            users,
+            user_lookups: SingleFlight::default(),
+            recent_messages,
+            concurrency,
+            blocking_permits,
+            library_events,
+            invocation_events,
+            disabled: RwLock::new(disabled),
+            saving: tokio::sync::Mutex::new(()),
+            user_queues: Mutex::new(HashMap::new()),
+            library_shutdowns: Mutex::new(HashMap::new()),
+            opened: Mutex::new(Vec::new()),
+            shutdown: CancellationToken::new(),
+            runtime,
+        }
+    }
+
+    /// Returns the metrics collected by this processor
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Returns the names of the libraries that are still open.
+    ///
+    /// An unloaded library stays open as long as something references it, a
+    /// library that was reloaded while its previous build is still in use is
+    /// listed once per build. More open than loaded libraries point to a leak.
+    pub fn open_libraries(&self) -> Vec<String> {
+        let mut opened = self.opened.lock();
+        opened.retain(|(_, library)| library.strong_count() > 0);
+        self.metrics.open_libraries.set(opened.len() as i64);
+        opened.iter().map(|(library_name, _)| library_name.clone()).collect()
+    }
+
+    /// Logs if more builds of a library are open than `expected`
+    fn check_closed(&self, library_name: &str, expected: usize) {
+        let open = self
+            .open_libraries()
+            .iter()
+            .filter(|open_library| *open_library == library_name)
+            .count();
+        if open > expected {
+            warn!(
+                library = library_name;
+                "{} builds of library {} are still open, expected {}", open, library_name, expected
+            );
         }
     }
 
+    /// Returns a receiver that tracks whether the processor is able to run commands.
+    ///
+    /// Whether the userservice is reachable is only known after a user lookup,
+    /// so it's updated with every lookup that isn't answered from the cache.
+    pub fn readiness(&self) -> watch::Receiver<Readiness> {
+        self.readiness.subscribe()
+    }
+
+    /// Runs the command a message invokes, returning the reply text of the command if it has one
     pub async fn call(
-        &self,
-        sender: &mut YouTubeServiceClient<Channel>,
-        user_client: &mut UserServiceClient<Channel>,
+        self: &Arc<Self>,
+        sender: &mut Outbound<T>,
+        user_client: &mut U,
         message: Message,
-    ) -> Result<(), ProcessorError> {
-        let lib_clone = self.libraries.clone();
-        let lib = lib_clone.lock().unwrap();
-        let lookup = lib
-            .values()
-            .find_map(|lib| lib.commands.get(&message.command_name));
+    ) -> Result<Option<String>, ProcessorError> {
+        self.call_with(message, sender, user_client).await
+    }
+
+    /// Dispatch a message to its command, handing the command the given services.
+    ///
+    /// A command name of the form `library::command` only looks the command up
+    /// in the given library, which disambiguates commands when several
+    /// libraries define the same name, see [`library_matches`].
+    ///
+    /// The services don't have to be the ones of the processor, tests pass
+    /// in-memory fakes of the chat platform and the userservice.
+    pub async fn call_with(
+        self: &Arc<Self>,
+        message: Message,
+        sender: &mut dyn YouTubeSendable,
+        users: &mut dyn UserLookup,
+    ) -> Result<Option<String>, ProcessorError> {
+        // Command names are matched case-insensitively, the table is keyed by lowercase names.
+        // Clone the proxy out of the table so the lock isn't held while the command runs
+        let command_key = message.command_name.to_lowercase();
+        let lookup = self.lookup(&command_key);
 
+        // Libraries may register their own help command, which takes precedence
+        if lookup.is_none() && self.config.help_command.as_deref() == Some(command_key.as_str()) {
+            return Ok(Some(self.help(&message)));
+        }
+        if lookup.is_none() && self.config.admin_commands {
+            if let Some(admin_command) = AdminCommand::from_name(&command_key) {
+                return self.admin(admin_command, &message).await.map(Some);
+            }
+        }
         if lookup.is_none() {
             return Err(ProcessorError::CommandNotFound {
                 command: message.command_name.clone(),
             });
         }
         let command = lookup.unwrap();
-        // let sender_mut = self.youtube_sender.lock();
-        // if sender_mut.is_err() {
-        // warn!("Previous lock of the YouTube client panicked! This might be from a command.");
-        // }
-        // let mut sender = sender_mut.unwrap();
-        let command_name = message.command_name.clone();
-        let raw_message = message.message.clone();
-        let library_name = command._lib_name.clone();
 
+        let channel_id = message.user.channel_id.clone();
+        let span = tracing::info_span!(
+            "call",
+            command = command.name.as_str(),
+            library = command._lib_name.as_str(),
+            channel_id = channel_id.as_str(),
+        );
         let mut service_directory = ServiceDirectory {
-            userservice_client: user_client,
+            userservice_client: users,
             youtubeservice_client: sender,
         };
-        let command_result = command.execute(message, &mut service_directory).await;
+        let result = self.run(&command, &mut service_directory, message).instrument(span).await;
+        let result_label = match &result {
+            Ok(_) => "success",
+            Err(err) => err.kind(),
+        };
+        self.metrics
+            .command_invocations
+            .with_label_values(&[&command.name, &command._lib_name, result_label])
+            .inc();
+        // Sending only fails if nobody is subscribed
+        let _ = self.invocation_events.send(InvocationEvent {
+            command: command.name.clone(),
+            library: command._lib_name.clone(),
+            channel_id,
+            result: result_label,
+            timestamp: chrono::Utc::now(),
+        });
 
-        if command_result.is_err() {
-            error!("{:?}", command_result.err().unwrap());
-            return Err(ProcessorError::CommandExecutionFailed {
-                command: command_name,
-                library: library_name,
-                message: raw_message,
+        result
+    }
+
+    /// Returns the library name and primary entry of every loaded command, sorted by name
+    pub fn commands(&self) -> Vec<(String, CommandProxy)> {
+        let mut commands: Vec<(String, CommandProxy)> = self
+            .index
+            .read()
+            .values()
+            .filter(|command| !command.is_alias)
+            .map(|command| (command._lib_name.clone(), command.clone()))
+            .collect();
+        commands.sort_by(|(_, a), (_, b)| a.name.cmp(&b.name));
+        commands
+    }
+
+    /// Whether an operator has disabled the command with the given primary name
+    pub fn is_enabled(&self, command_name: &str) -> bool {
+        !self.disabled.read().contains(&command_name.to_lowercase())
+    }
+
+    /// Enables or disables a command together with its aliases
+    pub async fn set_enabled(&self, command_name: &str, enabled: bool) -> Result<(), ProcessorError> {
+        let command = self.index.read().get(&command_name.to_lowercase()).cloned();
+        if command.is_none() {
+            return Err(ProcessorError::CommandNotFound {
+                command: command_name.to_string(),
             });
         }
+        let primary_name = command.unwrap().name.to_lowercase();
+
+        let changed = {
+            let mut disabled = self.disabled.write();
+            if enabled {
+                disabled.remove(&primary_name)
+            } else {
+                disabled.insert(primary_name)
+            }
+        };
 
+        if changed {
+            self.save_state().await;
+        }
         Ok(())
     }
 
-    pub async fn fetch_messages(&self) -> Void {
-        let sender = self.youtube_sender.lock();
+    /// Saves the disabled commands and the quota usage to the state file, if there is one.
+    ///
+    /// The file is written on the blocking thread pool. Saves run one after the
+    /// other and each takes its snapshot once it's its turn, so a save can't
+    /// overwrite the file with an older state than the previous save wrote.
+    pub async fn save_state(&self) {
+        if self.config.state_file.is_none() {
+            return;
+        }
+        let state_file = self.config.state_file.clone().unwrap();
+
+        let _saving = self.saving.lock().await;
+        let state = PersistedState {
+            disabled_commands: self.disabled.read().iter().cloned().collect(),
+            quota_usage: self.quotas.snapshot(),
+        };
+        let path = state_file.clone();
+        let save_result = tokio::task::spawn_blocking(move || state.save(&path)).await;
+        let save_result = match save_result {
+            Ok(save_result) => save_result.map_err(|err| err.to_string()),
+            Err(err) => Err(err.to_string()),
+        };
+        if save_result.is_err() {
+            error!("Unable to save state to {}: {}", state_file.display(), save_result.err().unwrap());
+        }
+    }
+
+    /// Saves the state every `CS_STATE_SAVE_INTERVAL_SECS` while the quota usage
+    /// changes, until `shutdown` is cancelled.
+    ///
+    /// Without it the usage would only be saved on shutdown, and a crash would
+    /// hand every user a fresh quota.
+    pub async fn save_state_periodically(&self, shutdown: CancellationToken) {
+        let interval = self.config.state_save_interval;
+        if self.config.state_file.is_none() || interval == Duration::ZERO {
+            return;
+        }
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick completes immediately, right after the state was loaded
+        ticks.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => return,
+                _ = ticks.tick() => {}
+            }
+            if self.quotas.has_changed() {
+                self.save_state().await;
+            }
+        }
+    }
+
+    /// Cooldown of a command, set by an operator or declared by the command
+    fn cooldown_of(&self, command: &CommandProxy) -> Duration {
+        self.cooldown_overrides
+            .read()
+            .get(&command.name.to_lowercase())
+            .copied()
+            .or(command.cooldown)
+            .unwrap_or(self.config.default_cooldown)
+    }
 
-        if sender.is_err() {
-            warn!("Previous lock of the YouTube client panicked! This might be from a command.");
+    /// Sets the cooldown of a command together with its aliases, zero disables it
+    pub fn set_cooldown(&self, command_name: &str, cooldown: Duration) -> Result<(), ProcessorError> {
+        let command = self.index.read().get(&command_name.to_lowercase()).cloned();
+        if command.is_none() {
+            return Err(ProcessorError::CommandNotFound {
+                command: command_name.to_string(),
+            });
         }
-        let mut sender = sender.unwrap();
+        let primary_name = command.unwrap().name.to_lowercase();
+
+        self.cooldown_overrides.write().insert(primary_name, cooldown);
+        Ok(())
+    }
+
+    /// Builds the reply of the built-in help command.
+    ///
+    /// Without an argument it lists the commands the user may run, otherwise it
+    /// describes the given command. Commands the user may not run are unknown
+    /// either way.
+    fn help(&self, message: &Message) -> String {
+        let prefix = &message.prefix;
+        let level = permissions::level_of(&message.user);
+        let index = self.index.read();
+        let is_available = |command: &CommandProxy| {
+            level >= command.permission_level && self.is_enabled(&command.name) && command.breaker.check().is_none()
+        };
 
-        let user_service = self.userservice_client.lock();
+        if let Some(argument) = message.arguments.first() {
+            let name = argument.strip_prefix(prefix.as_str()).unwrap_or(argument.as_str());
+            let command = index.get(&name.to_lowercase()).filter(|command| is_available(command));
+            if command.is_none() {
+                return format!("Unknown command {}", name);
+            }
+            let command = command.unwrap();
+            // Alias entries don't carry the aliases, the primary entry does
+            let command = index.get(&command.name.to_lowercase()).unwrap_or(command);
 
-        if user_service.is_err() {
-            warn!("Previous lock of the User service client panicked! This might be from a command.");
+            let mut reply = format!(
+                "{}{}: {}",
+                prefix,
+                command.name,
+                command.description.as_deref().unwrap_or(DEFAULT_DESCRIPTION)
+            );
+            if !command.aliases.is_empty() {
+                let aliases: Vec<String> = command
+                    .aliases
+                    .iter()
+                    .map(|alias| format!("{}{}", prefix, alias))
+                    .collect();
+                reply.push_str(&format!(" (aliases: {})", aliases.join(", ")));
+            }
+            return reply;
         }
-        let mut user_service = user_service.unwrap();
 
-        let mut stream = sender
-            .subscribe_messages(Request::new(()))
-            .await?
-            .into_inner();
+        let mut names: Vec<String> = index
+            .values()
+            .filter(|command| !command.is_alias && is_available(command))
+            .map(|command| format!("{}{}", prefix, command.name))
+            .collect();
+        names.sort();
+        if names.is_empty() {
+            return "No commands available".to_string();
+        }
+        format!("Commands: {}", names.join(", "))
+    }
 
-        while let Some(message) = stream.message().await? {
-            let channel_id = message.channel_id.clone();
-            let mut user = user_service
-                .get_user_by_id(Request::new(channel_id))
-                .await;
-            if user.is_err() {
-                let err = user.as_ref().err().unwrap();
-                if err.code() == tonic::Code::NotFound {
-                    debug!("User doesn't exist in userservice yet, waiting for 0.1 seconds and then trying again");
-                    tokio::time::sleep(Duration::from_millis(100)).await;
-                    let channel_id = message.channel_id.clone();
-                    user = user_service
-                        .get_user_by_id(Request::new(channel_id))
-                        .await;
-                    if user.is_err() {
-                        warn!("User doesn't exist in userservice, even with waiting, skipping message (this could also indicate the userservice not properly fetching users)");
-                        continue;
-                    }
+    /// Runs a built-in admin command and returns its reply.
+    ///
+    /// The role of the user has to grant the permission level of the command,
+    /// just like for the commands of the libraries.
+    async fn admin(self: &Arc<Self>, admin_command: AdminCommand, message: &Message) -> Result<String, ProcessorError> {
+        let channel_id = &message.user.channel_id;
+        if permissions::level_of(&message.user) < admin_command.permission_level() {
+            return Err(ProcessorError::PermissionDenied {
+                command: admin_command.name().to_string(),
+            });
+        }
+        let argument = message.arguments.first();
+        if let (None, Some(placeholder)) = (argument, admin_command.argument()) {
+            return Ok(format!("Usage: {}{} {}", message.prefix, admin_command.name(), placeholder));
+        }
+        info!(
+            command = admin_command.name(), channel_id = channel_id.as_str();
+            "Running admin command {} {}", admin_command.name(), message.arguments.join(" ")
+        );
+
+        match admin_command {
+            AdminCommand::Libraries => {
+                let mut library_names: Vec<String> = self.libraries.read().keys().cloned().collect();
+                if library_names.is_empty() {
+                    return Ok("No libraries are loaded".to_string());
                 }
+                library_names.sort();
+                Ok(format!("Libraries: {}", library_names.join(", ")))
             }
-            let user = user.unwrap();
-            let user = user.into_inner();
-
-            let command_message = Message::new(user.into(), message.message.clone());
-            if !command_message.has_command_info {
-                continue;
+            AdminCommand::Enable | AdminCommand::Disable => {
+                let command_name = argument.unwrap();
+                let enabled = admin_command == AdminCommand::Enable;
+                match self.set_enabled(command_name, enabled).await {
+                    Ok(()) if enabled => Ok(format!("Enabled {}", command_name)),
+                    Ok(()) => Ok(format!("Disabled {}", command_name)),
+                    Err(ProcessorError::CommandNotFound { .. }) => Ok(format!("Unknown command {}", command_name)),
+                    Err(err) => Err(err),
+                }
             }
-            let command_result = self
-                .call(&mut sender, &mut user_service, command_message)
+            AdminCommand::Reload => {
+                let library = argument.unwrap();
+                let namespace = library.to_lowercase();
+                let library_path = self
+                    .libraries
+                    .read()
+                    .values()
+                    .find(|registrar| library_matches(&registrar.library_name, &namespace))
+                    .map(|registrar| registrar.library_path.clone());
+                if library_path.is_none() {
+                    return Ok(format!("Library {} is not loaded", library));
+                }
+
+                // Same as the ReloadLibrary RPC, the library was loaded from this path before.
+                // Opening the new build blocks, so it runs off the async workers
+                let processor = self.clone();
+                let library_path = library_path.unwrap();
+                let reload_result = tokio::task::spawn_blocking(move || unsafe {
+                    processor.reload(&library_path)
+                })
                 .await;
-            if command_result.is_err() {
-                let error = command_result.err().unwrap();
-                // if error is CommandNotFound, we log in debug and continue
-                if let ProcessorError::CommandNotFound { command } = error {
-                    debug!("Command {} could not be found, skipping", command);
-                    continue;
-                } else {
-                    error!("{:?}", error);
+                match reload_result {
+                    Ok(Ok(())) => Ok(format!("Reloaded {}", library)),
+                    Ok(Err(err)) => Ok(format!("Unable to reload {}: {}", library, err)),
+                    Err(err) => Ok(format!("Unable to reload {}: {}", library, err)),
                 }
             }
         }
-
-        Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn unload<S: AsRef<str>>(&self, library_name: S) {
-        let lib_clone = self.libraries.clone();
-        let mut lib = lib_clone.lock().unwrap();
+    /// Checks whether the user may run a command right now and executes it
+    async fn run(
+        &self,
+        command: &CommandProxy,
+        service_directory: &mut ServiceDirectory<'_>,
+        message: Message,
+    ) -> Result<Option<String>, ProcessorError> {
+        let command_name = message.command_name.clone();
+        let raw_message = message.message.clone();
+        let library_name = command._lib_name.clone();
 
-        let registrar = lib.remove(library_name.as_ref());
-        if registrar.is_none() {
-            warn!(
-                "Library {} could not be found, skipping",
-                library_name.as_ref()
-            );
-            return;
+        let level = permissions::level_of(&message.user);
+        if level < command.permission_level {
+            return Err(ProcessorError::PermissionDenied {
+                command: command.name.clone(),
+            });
         }
-        let registrar = registrar.unwrap();
-        let registrar = Arc::<CommandRegistrar>::try_unwrap(registrar);
+        let bypass = self
+            .config
+            .bypass_level
+            .as_ref()
+            .map_or(false, |bypass_level| level >= *bypass_level);
 
-        if registrar.is_err() {
-            error!("Error while trying to take ownership of command registrar {} (maybe it's still used somewhere?)", library_name.as_ref());
-            lib
-                .insert(library_name.as_ref().to_string(), registrar.err().unwrap());
-            return;
+        if !self.is_enabled(&command.name) {
+            return Err(ProcessorError::Disabled {
+                command: command.name.clone(),
+            });
         }
-        let mut registrar = registrar.ok().unwrap();
-        let commands: HashMap<String, CommandProxy> = registrar.commands.drain().collect();
 
-        let library = Arc::<Library>::try_unwrap(registrar.lib);
-        if library.is_err() {
-            error!("Error while trying to take ownership of library {} (maybe it's still used somewhere?)", library_name.as_ref());
-            let registrar = Arc::new(CommandRegistrar {
-                lib: library.err().unwrap(),
-                commands,
-                library_name: library_name.as_ref().to_string(),
+        if let Some(remaining) = command.breaker.check() {
+            return Err(ProcessorError::CircuitOpen {
+                command: command.name.clone(),
+                remaining_secs: remaining.as_secs() + (remaining.subsec_nanos() > 0) as u64,
             });
+        }
 
-            lib
-                .insert(library_name.as_ref().to_string(), registrar);
-            return;
+        // Aliases share the cooldown of their command
+        let cooldown = self.cooldown_of(&command);
+        if bypass && (cooldown > Duration::ZERO || self.user_limiter.is_some()) {
+            debug!(
+                channel_id = message.user.channel_id.as_str(), command = command.name.as_str();
+                "User {} bypasses the cooldown and rate limit of command {}", message.user.channel_id, command.name
+            );
         }
-        let library = library.ok().unwrap();
+        // Users who bypass the cooldown don't start it for everyone else either
+        let cooldown = if bypass { Duration::ZERO } else { cooldown };
 
-        let success = library.close();
-        if success.is_err() {
-            let err = success.err().unwrap();
+        if let (Some(user_limiter), false) = (&self.user_limiter, bypass) {
+            let channel_id = &message.user.channel_id;
+            if !user_limiter.try_acquire(channel_id) {
+                return Err(ProcessorError::RateLimited {
+                    channel_id: channel_id.clone(),
+                    reason: "is sending commands too fast".to_string(),
+                });
+            }
+        }
+
+        if cooldown > Duration::ZERO {
+            let last_invocation = self.cooldowns.lock().get(&command.name.to_lowercase()).copied();
+            if let Some(last_invocation) = last_invocation {
+                let elapsed = last_invocation.elapsed();
+                if elapsed < cooldown {
+                    let remaining = cooldown - elapsed;
+                    return Err(ProcessorError::Cooldown {
+                        command: command.name.clone(),
+                        remaining_secs: remaining.as_secs() + (remaining.subsec_nanos() > 0) as u64,
+                    });
+                }
+            }
+        }
+
+        // Held until the command finished
+        let _permit = match &command.concurrency {
+            None => None,
+            Some(concurrency) => match self.config.command_overload_policy {
+                OverloadPolicy::Wait => Some(concurrency.clone().acquire_owned().await.unwrap()),
+                OverloadPolicy::Drop => {
+                    let permit = concurrency.clone().try_acquire_owned();
+                    if permit.is_err() {
+                        return Err(ProcessorError::RateLimited {
+                            channel_id: message.user.channel_id.clone(),
+                            reason: format!(
+                                "can't run command {} while it's already running {} times",
+                                command.name,
+                                command.max_concurrency.unwrap_or_default()
+                            ),
+                        });
+                    }
+                    Some(permit.unwrap())
+                }
+            },
+        };
+
+        // Counted once the command is about to run, so rejected invocations don't use up the quota
+        let quota_result = self.quotas.try_use(&command.name, &message.user.channel_id);
+        if quota_result.is_err() {
+            let reset = quota_result.err().unwrap();
+            return Err(ProcessorError::RateLimited {
+                channel_id: message.user.channel_id.clone(),
+                reason: format!(
+                    "used up the quota of command {}, it resets in {} seconds",
+                    command.name,
+                    reset.as_secs()
+                ),
+            });
+        }
+
+        // A panicking command must not unwind into the message loop
+        let started = Instant::now();
+        let span = tracing::info_span!("execute", error = tracing::field::Empty, timed_out = false);
+        // Counted until the command returns, so the library isn't closed under it
+        let in_flight = command.in_flight.enter();
+        let execution = if command.blocking {
+            self.execute_blocking(command, message, in_flight).boxed()
+        } else {
+            let cancellation = self.library_cancellation(&command._lib_name);
+            async move {
+                let _in_flight = in_flight;
+                AssertUnwindSafe(command.execute_cancellable(message, service_directory, cancellation))
+                    .catch_unwind()
+                    .await
+            }
+            .boxed()
+        }
+        .instrument(span.clone());
+        // `None` if the command ran out of time
+        let command_result = match self.config.command_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, execution).await.ok(),
+            None => Some(execution.await),
+        };
+        // The error is kept in the stats, so it can be looked up without the logs
+        let (result_label, last_error) = match &command_result {
+            Some(Ok(Ok(_))) => ("success", None),
+            Some(Ok(Err(error))) => ("command_execution_failed", Some(format!("Command failed: {:?}", error))),
+            Some(Err(_)) => ("command_panicked", Some("Command panicked".to_string())),
+            None => (
+                "timeout",
+                Some(format!("Command timed out after {:?}", self.config.command_timeout.unwrap())),
+            ),
+        };
+        if command_result.is_none() {
+            span.record("timed_out", &true);
+        }
+        if result_label != "success" {
+            span.record("error", &result_label);
+        }
+        self.metrics
+            .command_execution
+            .with_label_values(&[&command.name, result_label])
+            .observe(started.elapsed().as_secs_f64());
+        self.stats.record(&command.name, last_error);
+        let tripped = command.breaker.record(
+            result_label == "success",
+            self.config.breaker_threshold,
+            self.config.breaker_cooldown,
+        );
+        if tripped {
+            warn!(
+                command = command.name.as_str(), library = library_name.as_str();
+                "Command failed {} times in a row, disabling it for {:?}",
+                self.config.breaker_threshold, self.config.breaker_cooldown
+            );
+        }
+
+        if command_result.is_none() {
+            let timeout = self.config.command_timeout.unwrap();
+            error!(command = command_name.as_str(), library = library_name.as_str(); "Command timed out after {:?}", timeout);
+            return Err(ProcessorError::Timeout {
+                command: command_name,
+                timeout_secs: timeout.as_secs(),
+            });
+        }
+        let command_result = command_result.unwrap();
+
+        if command_result.is_err() {
+            error!(command = command_name.as_str(), library = library_name.as_str(); "Command panicked");
+            return Err(ProcessorError::CommandPanicked {
+                command: command_name,
+                library: library_name,
+            });
+        }
+        let command_result = command_result.unwrap();
+
+        if command_result.is_err() {
+            error!(
+                command = command_name.as_str(), library = library_name.as_str();
+                "Command failed: {:?}", command_result.err().unwrap()
+            );
+            return Err(ProcessorError::CommandExecutionFailed {
+                command: command_name,
+                library: library_name,
+                message: raw_message,
+            });
+        }
+
+        if cooldown > Duration::ZERO {
+            self.cooldowns.lock().insert(command.name.to_lowercase(), Instant::now());
+        }
+
+        Ok(command_result.unwrap())
+    }
+
+    /// Runs a blocking command on the blocking thread pool of the runtime.
+    ///
+    /// Blocking commands get no service directory, the clients are bound to the
+    /// async runtime, so they can only answer through their reply text. The
+    /// number of blocking commands running at once is limited by
+    /// `CS_BLOCKING_THREADS`, further invocations wait for a free slot. A timeout
+    /// stops waiting for the command, but the thread keeps running and keeps its
+    /// slot until the command returns.
+    async fn execute_blocking(&self, command: &CommandProxy, message: Message, in_flight: InFlightGuard) -> Result<Result<Option<String>, CommandError>, Box<dyn Any + Send>> {
+        let permit = self.blocking_permits.clone().acquire_owned().await.unwrap();
+        // The clone keeps the library loaded while the thread runs, the guard keeps
+        // it counted and the permit keeps its slot taken even after a timeout
+        // stopped waiting for it
+        let command = command.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let _in_flight = in_flight;
+            command.command.execute_blocking(message)
+        })
+        .await;
+        result.map_err(|err| {
+            // Blocking tasks are only cancelled when the runtime shuts down
+            err.try_into_panic()
+                .unwrap_or_else(|err| -> Box<dyn Any + Send> { Box::new(err.to_string()) })
+        })
+    }
+
+    /// Subscribe to the chat platform and process incoming messages.
+    ///
+    /// The subscription is renewed whenever the stream errors or ends, so a
+    /// restart of the platform service doesn't stop command processing. Attempts
+    /// are spaced out with an exponential backoff, which is reset as soon as a
+    /// message has been received again.
+    ///
+    /// Once `shutdown` is cancelled no new messages are read, but commands that
+    /// are already running get to finish before this returns.
+    pub async fn fetch_messages(self: &Arc<Self>, shutdown: CancellationToken) -> Void {
+        // Messages are handled on per-user worker tasks, so a slow command doesn't stall the stream
+        let mut tasks = FuturesUnordered::new();
+        let result = self.resubscribe_messages(&mut tasks, &shutdown).await;
+        // Commands that support it can wrap up early instead of using up the shutdown timeout
+        if shutdown.is_cancelled() {
+            self.shutdown.cancel();
+        }
+
+        while let Some(task_result) = tasks.next().await {
+            log_task_result(task_result);
+        }
+        result
+    }
+
+    async fn resubscribe_messages(self: &Arc<Self>, tasks: &mut FuturesUnordered<JoinHandle<()>>, shutdown: &CancellationToken) -> Void {
+        let mut delay = self.config.reconnect_base;
+        loop {
+            let mut received = false;
+            let stream_result = self.process_message_stream(tasks, &mut received, shutdown).await;
+            self.readiness.update(|readiness| readiness.stream_connected = false);
+            if shutdown.is_cancelled() {
+                return Ok(());
+            }
+            if received {
+                delay = self.config.reconnect_base;
+            }
+
+            // Jitter keeps multiple instances from resubscribing in lockstep
+            let half = delay / 2;
+            let jittered = half + half.mul_f64(rand::random::<f64>());
+            if stream_result.is_err() {
+                warn!("Message stream failed, resubscribing in {:?}: {}", jittered, stream_result.err().unwrap());
+            } else {
+                warn!("Message stream ended, resubscribing in {:?}", jittered);
+            }
+            let sleep = tokio::time::sleep(jittered);
+            tokio::pin!(sleep);
+            loop {
+                tokio::select! {
+                    _ = &mut sleep => break,
+                    Some(task_result) = tasks.next(), if !tasks.is_empty() => log_task_result(task_result),
+                    _ = shutdown.cancelled() => return Ok(()),
+                }
+            }
+            delay = std::cmp::min(delay * 2, self.config.reconnect_max);
+        }
+    }
+
+    /// Subscribe to the chat platform once and dispatch messages until the stream ends.
+    ///
+    /// `received` is set once the first message came through.
+    async fn process_message_stream(
+        self: &Arc<Self>,
+        tasks: &mut FuturesUnordered<JoinHandle<()>>,
+        received: &mut bool,
+        shutdown: &CancellationToken,
+    ) -> Void {
+        let mut sender = self.sender.clone();
+        let mut stream = sender.subscribe_messages().await?;
+        self.readiness.update(|readiness| readiness.stream_connected = true);
+
+        loop {
+            let message = tokio::select! {
+                message = stream.next() => message.transpose()?,
+                Some(task_result) = tasks.next(), if !tasks.is_empty() => {
+                    log_task_result(task_result);
+                    continue;
+                }
+                _ = shutdown.cancelled() => return Ok(()),
+            };
+            if message.is_none() {
+                break;
+            }
+            let message = message.unwrap();
+            *received = true;
+            self.metrics.messages_processed.inc();
+            // Replies of the bot that look like commands would otherwise trigger more replies
+            if self.config.bot_channel_id.as_deref() == Some(message.channel_id.as_str()) {
+                debug!("Skipping message of the bot itself");
+                continue;
+            }
+            if self.config.is_too_long(&message.message) {
+                debug!(
+                    "Skipping message of {} with more than {} characters",
+                    message.channel_id, self.config.max_message_len
+                );
+                continue;
+            }
+            // Messages that can't be commands don't need a user lookup
+            let trigger = match self.config.match_prefix(&message.message) {
+                // A prefixed message that doesn't name a command might still match a
+                // pattern, otherwise it goes on to suggest a command
+                Some(prefix) if !self.has_command(args::command_name(&message.message, prefix)) => self
+                    .match_trigger(&message.message)
+                    .unwrap_or_else(|| Trigger::Prefix(prefix.to_string())),
+                Some(prefix) => Trigger::Prefix(prefix.to_string()),
+                None => match self.match_trigger(&message.message) {
+                    Some(trigger) => trigger,
+                    None => continue,
+                },
+            };
+            if self.recent_messages.is_duplicate(&message.channel_id, &message.message) {
+                debug!("Skipping message of {} that was delivered again", message.channel_id);
+                continue;
+            }
+            let correlation_id = uuid::Uuid::new_v4().to_string();
+
+            let permit = match self.config.overload_policy {
+                OverloadPolicy::Wait => tokio::select! {
+                    permit = self.concurrency.clone().acquire_owned() => permit.unwrap(),
+                    _ = shutdown.cancelled() => return Ok(()),
+                },
+                OverloadPolicy::Drop => {
+                    let permit = self.concurrency.clone().try_acquire_owned();
+                    if permit.is_err() {
+                        warn!(
+                            correlation_id = correlation_id.as_str();
+                            "{} messages are already being processed, dropping message", self.config.max_concurrency
+                        );
+                        continue;
+                    }
+                    permit.unwrap()
+                }
+            };
+
+            let worker = self.enqueue(QueuedMessage { message, correlation_id, trigger, _permit: permit });
+            if let Some(worker) = worker {
+                tasks.push(worker);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Finds the first command, by name, whose trigger pattern matches a message.
+    ///
+    /// Only used for messages without a prefix and prefixed messages that don't
+    /// name a command, prefixed commands never go through the patterns.
+    fn match_trigger(&self, message: &str) -> Option<Trigger> {
+        let triggers = self.triggers.read();
+        triggers.iter().find_map(|(command, pattern)| {
+            let captures = pattern.captures(message)?;
+            let arguments = captures
+                .iter()
+                .skip(1)
+                .map(|capture| capture.map_or(String::new(), |capture| capture.as_str().to_string()))
+                .collect();
+            Some(Trigger::Pattern { command: command.clone(), arguments })
+        })
+    }
+
+    /// Queue a message behind the other messages of the same user.
+    ///
+    /// Messages of one user are processed in order, so stateful commands don't
+    /// race each other, while different users are processed in parallel. Returns
+    /// the worker task if the user had no messages queued yet.
+    fn enqueue(self: &Arc<Self>, queued: QueuedMessage) -> Option<JoinHandle<()>> {
+        let channel_id = queued.message.channel_id.clone();
+        let mut queues = self.user_queues.lock();
+        if let Some(queue) = queues.get(&channel_id) {
+            // The worker only exits while holding the lock, so the queue is still open
+            let _ = queue.send(queued);
+            return None;
+        }
+
+        let (queue, mut receiver) = mpsc::unbounded_channel();
+        let _ = queue.send(queued);
+        queues.insert(channel_id.clone(), queue);
+        drop(queues);
+
+        // tonic clients are cheap to clone, every worker gets its own
+        let processor = self.clone();
+        let sender = self.sender.clone();
+        let user_service = self.userservice_client.clone();
+        Some(tokio::spawn(async move {
+            loop {
+                let next = {
+                    let mut queues = processor.user_queues.lock();
+                    let next = receiver.try_recv();
+                    if next.is_err() {
+                        queues.remove(&channel_id);
+                        return;
+                    }
+                    next.unwrap()
+                };
+                // Every message starts its own trace, covering the user lookup and the command
+                let span = tracing::info_span!(
+                    parent: None,
+                    "message",
+                    channel_id = next.message.channel_id.as_str(),
+                    correlation_id = next.correlation_id.as_str(),
+                );
+                let processing = processor
+                    .process_message(next.message, next.trigger, sender.clone(), user_service.clone())
+                    .instrument(span);
+                // Log lines of the message carry its correlation id
+                CORRELATION_ID.scope(next.correlation_id, processing).await;
+            }
+        }))
+    }
+
+    /// Look up the user of a message and run the command it invokes
+    async fn process_message(self: &Arc<Self>, message: ChatMessage, trigger: Trigger, mut sender: Outbound<T>, mut user_service: U) {
+        let user = self.lookup_user(&mut user_service, &message.channel_id).await;
+        if user.is_none() {
+            return;
+        }
+        let (user, degraded) = user.unwrap();
+
+        let command_message = match trigger {
+            Trigger::Prefix(prefix) => {
+                // The matched prefix is kept on the message, so commands know how they were invoked
+                let mut command_message = Message::with_prefix(user.into(), message.message, &prefix);
+                if !command_message.has_command_info {
+                    return;
+                }
+                command_message.arguments = args::command_arguments(&command_message.message, &prefix);
+                command_message
+            }
+            Trigger::Pattern { command, arguments } => {
+                // Without a prefix, the message doesn't name the command
+                let mut command_message = Message::with_prefix(user.into(), message.message, "");
+                command_message.has_command_info = true;
+                command_message.command_name = command;
+                command_message.arguments = arguments;
+                command_message
+            }
+        };
+        if degraded && self.requires_user(&command_message.command_name) {
+            debug!("Command {} needs user data, skipping it while the userservice is unavailable", command_message.command_name);
+            return;
+        }
+        // Messages of trigger patterns have no prefix to suggest a command with
+        let prefix = command_message.prefix.clone();
+        let command_result = self
+            .call(&mut sender, &mut user_service, command_message)
+            .await;
+        if let Ok(Some(reply)) = &command_result {
+            // Commands that return their reply instead of sending it themselves
+            let send_result = sender.send_message(reply).await;
+            if send_result.is_err() {
+                error!("Unable to send reply: {}", send_result.err().unwrap());
+            }
+        }
+        if command_result.is_err() {
+            let error = command_result.err().unwrap();
+            // if error is CommandNotFound, we log in debug and continue
+            match error {
+                ProcessorError::CommandNotFound { command } => {
+                    debug!("Command {} could not be found, skipping", command);
+                    if self.config.suggestions && !prefix.is_empty() {
+                        self.suggest(&mut sender, &command, &prefix).await;
+                    }
+                }
+                ProcessorError::Cooldown { .. }
+                | ProcessorError::RateLimited { .. }
+                | ProcessorError::PermissionDenied { .. }
+                | ProcessorError::CircuitOpen { .. }
+                | ProcessorError::Disabled { .. } => {
+                    debug!("{}", error);
+                }
+                _ => error!("{:?}", error),
+            }
+        }
+    }
+
+    /// Reply with the closest known command or alias to an unknown command name
+    async fn suggest(&self, sender: &mut Outbound<T>, command_name: &str, prefix: &str) {
+        let suggestion = {
+            let index = self.index.read();
+            suggest::closest(
+                &command_name.to_lowercase(),
+                index.keys().map(String::as_str),
+                self.config.suggest_distance,
+            )
+            .map(str::to_string)
+        };
+        if suggestion.is_none() {
+            return;
+        }
+
+        let reply = format!("Did you mean {}{}?", prefix, suggestion.unwrap());
+        let send_result = sender.send_message(&reply).await;
+        if send_result.is_err() {
+            error!("Unable to send suggestion: {}", send_result.err().unwrap());
+        }
+    }
+
+    /// Whether the command with the given name or alias needs user data from the userservice
+    fn requires_user(&self, command_name: &str) -> bool {
+        self.lookup(&command_name.to_lowercase())
+            .map_or(false, |command| command.requires_user)
+    }
+
+    /// Whether a command name or alias, or the name of a built-in command, is known
+    fn has_command(&self, command_name: &str) -> bool {
+        let command_key = command_name.to_lowercase();
+        self.lookup(&command_key).is_some()
+            || self.config.help_command.as_deref() == Some(command_key.as_str())
+            || (self.config.admin_commands && AdminCommand::from_name(&command_key).is_some())
+    }
+
+    /// Finds a command by its lowercase name or alias, which may be namespaced
+    /// with the library as `library::command`
+    pub fn lookup(&self, command_key: &str) -> Option<CommandProxy> {
+        match command_key.split_once(NAMESPACE_SEPARATOR) {
+            Some((namespace, name)) => self
+                .libraries
+                .read()
+                .iter()
+                .find(|(library_name, _)| library_matches(library_name, namespace))
+                .and_then(|(_, registrar)| registrar.commands.get(name).cloned()),
+            None => self.index.read().get(command_key).cloned(),
+        }
+    }
+
+    /// Looks up the user of a message, retrying while the userservice doesn't
+    /// know the user yet or can't be reached.
+    ///
+    /// Returns the user and whether it's only a minimal stand-in because the
+    /// userservice stayed unreachable, or `None` if the message has to be skipped.
+    async fn lookup_user(&self, user_service: &mut U, channel_id: &str) -> Option<(BppUser, bool)> {
+        let mut attempt = 0;
+        let mut delay = self.config.user_retry_delay;
+        loop {
+            let err = match self.get_user(user_service, channel_id).await {
+                Ok(user) => return Some((user, false)),
+                Err(err) => err,
+            };
+            let not_found = err.code() == tonic::Code::NotFound;
+
+            if (not_found || is_unreachable(&err)) && attempt < self.config.user_retries {
+                attempt += 1;
+                if not_found {
+                    // New users may not be registered by the userservice yet
+                    debug!(
+                        "User doesn't exist in userservice yet, waiting for {:?} and then trying again (attempt {}/{})",
+                        self.config.user_retry_delay, attempt, self.config.user_retries
+                    );
+                    tokio::time::sleep(self.config.user_retry_delay).await;
+                } else {
+                    // The userservice may just be restarting, so it gets a few chances with growing delays
+                    debug!(
+                        "Userservice is unavailable, waiting for {:?} and then trying again (attempt {}/{})",
+                        delay, attempt, self.config.user_retries
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                continue;
+            }
+
+            if not_found {
+                warn!("User doesn't exist in userservice, even with waiting, skipping message (this could also indicate the userservice not properly fetching users)");
+                return None;
+            }
+            if is_unreachable(&err) {
+                // Commands that don't need the user data can still run with what the message tells us
+                warn!("Userservice is unavailable, continuing with a minimal user: {}", err.message());
+                self.metrics.degraded_user_lookups.inc();
+                let user = BppUser {
+                    channel_id: channel_id.to_string(),
+                    ..Default::default()
+                };
+                return Some((user, true));
+            }
+            warn!("Unable to look up user, skipping message: {}", err);
+            return None;
+        }
+    }
+
+    /// Looks up a user, going to the userservice only if the user isn't cached.
+    ///
+    /// Concurrent lookups of the same user share a single request to the
+    /// userservice, even with the cache turned off.
+    async fn get_user(&self, user_service: &mut U, channel_id: &str) -> Result<BppUser, tonic::Status> {
+        if let Some(user) = self.users.get(channel_id) {
+            return Ok(user);
+        }
+
+        let mut user_service = user_service.clone();
+        let lookup_channel_id = channel_id.to_string();
+        // Statuses can't be cloned, waiting lookups get their code and message
+        let user = self
+            .user_lookups
+            .run(channel_id, || async move {
+                user_service
+                    .get_user(&lookup_channel_id)
+                    .await
+                    .map_err(|err| (err.code(), err.message().to_string()))
+            })
+            .await
+            .map_err(|(code, message)| tonic::Status::new(code, message));
+        let reachable = user.as_ref().err().map_or(true, |err| !is_unreachable(err));
+        self.readiness.update(|readiness| readiness.userservice_reachable = reachable);
+        let user = user?;
+        self.users.insert(channel_id, user.clone());
+        Ok(user)
+    }
+
+    /// Remove a library from the command table and close it.
+    ///
+    /// The periodic tasks of the library are stopped, its running commands are
+    /// asked to stop and its `on_unload` hook runs right before it's closed.
+    ///
+    /// Commands are asked to stop by cancelling the token they get in
+    /// `execute_cancellable`. This is cooperative, commands that don't check the
+    /// token keep running. Closing the library while its code runs would be
+    /// undefined behavior, so the library is only closed once none of its
+    /// commands are executing anymore. If they are still running after
+    /// `CS_UNLOAD_TIMEOUT_SECS`, the library stays loaded without running the
+    /// hook and [`ProcessorError::LibraryInUse`] is returned.
+    pub async fn unload<S: AsRef<str>>(&self, library_name: S) -> Result<(), ProcessorError> {
+        self.unload_within(library_name.as_ref(), self.config.unload_timeout, false).await
+    }
+
+    /// Unload a library, waiting for its commands that are still running.
+    ///
+    /// Unlike [`CommandProcessor::unload`], closing the library is retried until
+    /// `CS_FORCE_UNLOAD_TIMEOUT_SECS` passes, so references that outlive the
+    /// commands, like a blocking command that timed out, have time to go away.
+    /// The library stays out of the command table while waiting, so no new
+    /// invocations of its commands start. If it's still in use afterwards, it's
+    /// put back and [`ProcessorError::LibraryInUse`] is returned.
+    pub async fn force_unload<S: AsRef<str>>(&self, library_name: S) -> Result<(), ProcessorError> {
+        self.unload_within(library_name.as_ref(), self.config.force_unload_timeout, true).await
+    }
+
+    /// Removes a library from the command table, waits up to `timeout` for its
+    /// running commands and closes it, retrying until `timeout` if `retry` is set
+    async fn unload_within(&self, library_name: &str, timeout: Duration, retry: bool) -> Result<(), ProcessorError> {
+        let registrar = {
+            let mut lib = self.libraries.write();
+            let registrar = lib.remove(library_name);
+            if registrar.is_none() {
+                warn!("Library {} could not be found, skipping", library_name);
+                return Err(ProcessorError::LibraryNotFound {
+                    library_name: library_name.to_string(),
+                });
+            }
+            // The index holds clones of the proxies, which keep the library alive
+            self.rebuild_index(&lib);
+            registrar.unwrap()
+        };
+        self.stop_library(library_name);
+
+        let deadline = Instant::now() + timeout;
+        // Closing the library while one of its commands executes would unmap the code under it
+        let in_flight = registrar.in_flight.clone();
+        if !in_flight.wait_idle(timeout).await {
+            warn!(
+                "Library {} still has {} commands running after {:?}, keeping it loaded",
+                library_name,
+                in_flight.count(),
+                timeout
+            );
+            self.restore(library_name, registrar);
+            return Err(ProcessorError::LibraryInUse {
+                library_name: library_name.to_string(),
+            });
+        }
+
+        let mut registrar = registrar;
+        loop {
+            let close_result = self.close(library_name, registrar);
+            if close_result.is_ok() {
+                break;
+            }
+            registrar = close_result.err().unwrap();
+
+            if !retry || Instant::now() >= deadline {
+                self.restore(library_name, registrar);
+                return Err(ProcessorError::LibraryInUse {
+                    library_name: library_name.to_string(),
+                });
+            }
+            debug!("Library {} is still in use, retrying in {:?}", library_name, FORCE_UNLOAD_RETRY_INTERVAL);
+            tokio::time::sleep(FORCE_UNLOAD_RETRY_INTERVAL).await;
+        }
+        // The library might have been loaded again in the meantime
+        let loaded = self.libraries.read().contains_key(library_name) as usize;
+        self.check_closed(library_name, loaded);
+
+        let _ = self.library_events.send(LibraryEvent::Unloaded {
+            library_name: library_name.to_string(),
+        });
+        Ok(())
+    }
+
+    /// Puts a library that couldn't be unloaded back into the command table
+    fn restore(&self, library_name: &str, registrar: Arc<CommandRegistrar>) {
+        let mut lib = self.libraries.write();
+        // The library might have been loaded again while waiting
+        if lib.contains_key(library_name) {
+            return;
+        }
+        self.start_library(&registrar);
+        lib.insert(library_name.to_string(), registrar);
+        self.rebuild_index(&lib);
+    }
+
+    /// Rebuilds the command index from the given libraries
+    fn rebuild_index(&self, lib: &HashMap<String, Arc<CommandRegistrar>>) {
+        let mut index = self.index.write();
+        index.clear();
+        for registrar in lib.values() {
+            for (name, command) in &registrar.commands {
+                index.insert(name.clone(), command.clone());
+            }
+        }
+        let mut triggers: Vec<(String, Arc<Regex>)> = index
+            .values()
+            .filter(|command| !command.is_alias)
+            .filter_map(|command| Some((command.name.clone(), command.trigger.clone()?)))
+            .collect();
+        triggers.sort_by(|(a, _), (b, _)| a.cmp(b));
+        *self.triggers.write() = triggers;
+        self.readiness.update(|readiness| readiness.libraries_loaded = !lib.is_empty());
+    }
+
+    /// Closes the library behind a registrar that has already been removed from
+    /// the command table.
+    ///
+    /// If the registrar or its library is still referenced somewhere, it is handed
+    /// back so the caller can decide what to do with it.
+    fn close(&self, library_name: &str, registrar: Arc<CommandRegistrar>) -> Result<(), Arc<CommandRegistrar>> {
+        let registrar = Arc::<CommandRegistrar>::try_unwrap(registrar);
+
+        if registrar.is_err() {
+            let registrar = registrar.err().unwrap();
+            error!(
+                "Error while trying to take ownership of command registrar {} (maybe it's still used somewhere?), it's referenced {} times",
+                library_name,
+                Arc::strong_count(&registrar)
+            );
+            return Err(registrar);
+        }
+        let mut registrar = registrar.ok().unwrap();
+
+        // Every proxy of the registrar holds a reference to the library, any other
+        // reference is a command that is still running. The library isn't in the
+        // command table anymore, so no new references can be taken once they're gone.
+        let outstanding = Arc::strong_count(&registrar.lib) - registrar.commands.len() - 1;
+        if outstanding > 0 {
+            error!(
+                "Error while trying to take ownership of library {} (maybe it's still used somewhere?), it's referenced {} times outside of its registrar",
+                library_name,
+                outstanding
+            );
+            return Err(Arc::new(registrar));
+        }
+
+        // The commands and tasks are implemented by the library, so they have to
+        // be dropped before it's closed
+        registrar.commands.clear();
+        registrar.tasks.clear();
+        registrar.run_unload_hook();
+
+        let library = Arc::<Library>::try_unwrap(registrar.lib);
+        if library.is_err() {
+            // Dropping the last reference closes the library as well
+            error!("Library {} was referenced again while it was closed, it will be closed once it's no longer in use", library_name);
+            return Ok(());
+        }
+        let library = library.ok().unwrap();
+
+        let success = library.close();
+        if success.is_err() {
+            let err = success.err().unwrap();
             error!(
                 "An unrecoverable error occurred while unloading {}: {:?}",
-                library_name.as_ref(),
+                library_name,
                 err
             );
-            error!(
-                "It might be a good idea to restart the service to prevent possible memory leaks!"
+            error!(
+                "It might be a good idea to restart the service to prevent possible memory leaks!"
+            );
+            error!("Unlike all of the other errors that occur, this one will prevent the commands from this library to run in order to prevent the service from panicking!");
+        }
+
+        Ok(())
+    }
+
+    /// Load a plugin library and add all contained functions to the internal
+    /// function table.
+    ///
+    /// Fails with [`ProcessorError::AlreadyLoaded`] if a library with the same
+    /// file name is loaded already.
+    ///
+    /// The `on_load` hook of the library runs before it registers its commands,
+    /// if it fails the library isn't loaded. `on_unload` runs right before the
+    /// library is closed, see [`CommandProcessor::unload`].
+    ///
+    /// # Safety
+    ///
+    /// A plugin library **must** be implemented using the
+    /// [`bpp_command_api::export_command!()`] macro. Trying manually implement
+    /// a plugin without going through that macro will result in undefined
+    /// behavior.
+    pub unsafe fn load<P: AsRef<OsStr>>(&self, library_path: P) -> Result<(), ProcessorError> {
+        let result = self.try_load(&library_path);
+        self.publish_load_result(library_path.as_ref(), result.as_ref().map(|_| ()), LibraryEvent::Loaded);
+        result
+    }
+
+    unsafe fn try_load<P: AsRef<OsStr>>(&self, library_path: P) -> Result<(), ProcessorError> {
+        let path: PathBuf = library_path.as_ref().into();
+        let file_name = path.file_name().unwrap().to_str().unwrap().to_string();
+        // Replacing the entry would drop the old library without closing it properly,
+        // use `reload` to replace a loaded library
+        if self.libraries.read().contains_key(&file_name) {
+            return Err(ProcessorError::AlreadyLoaded { library_name: file_name });
+        }
+        let registrar = self.open(library_path, path)?;
+        self.insert_library(registrar)
+    }
+
+    /// Registers commands without a library file, as if a library named
+    /// `library_name` had registered them.
+    ///
+    /// The commands are part of this process, which stands in for their library,
+    /// so they are unloaded and listed like the commands of any other library.
+    /// Tests and benchmarks use this to work with fake commands.
+    pub fn load_in_process<F>(&self, library_name: &str, register: F) -> Result<(), ProcessorError>
+    where
+        F: FnOnce(&mut dyn bpp_command_api::traits::CommandRegistrar),
+    {
+        let result = self.try_load_in_process(library_name, register);
+        self.publish_load_result(OsStr::new(library_name), result.as_ref().map(|_| ()), LibraryEvent::Loaded);
+        result
+    }
+
+    fn try_load_in_process<F>(&self, library_name: &str, register: F) -> Result<(), ProcessorError>
+    where
+        F: FnOnce(&mut dyn bpp_command_api::traits::CommandRegistrar),
+    {
+        let library = this_process();
+        if library.is_err() {
+            return Err(ProcessorError::LoadError {
+                library_name: library_name.to_string(),
+                message: library.err().unwrap().to_string(),
+            });
+        }
+        let library = Arc::new(library.unwrap());
+        self.track_open(library_name, &library);
+
+        let mut registrar = CommandRegistrar::new(library, library_name.to_string(), PathBuf::from(library_name), Vec::new(), None);
+        register(&mut registrar);
+        self.check_collisions(&mut registrar)?;
+        self.insert_library(registrar)
+    }
+
+    /// Adds the commands of an opened library to the command table and starts its tasks
+    fn insert_library(&self, mut registrar: CommandRegistrar) -> Result<(), ProcessorError> {
+        let lib_clone = self.libraries.clone();
+        let mut lib = lib_clone.write();
+        // Another load of the same library might have finished in the meantime,
+        // `on_load` already ran for this copy, so it gets its `on_unload` as well
+        if lib.contains_key(&registrar.library_name) {
+            registrar.run_unload_hook();
+            return Err(ProcessorError::AlreadyLoaded { library_name: registrar.library_name });
+        }
+        let conflicts = self.resolve_conflicts(&lib, &mut registrar);
+        if conflicts.is_err() {
+            registrar.run_unload_hook();
+            return Err(conflicts.err().unwrap());
+        }
+        let registrar = Arc::new(registrar);
+        self.start_library(&registrar);
+        lib
+            .insert(registrar.library_name.clone(), registrar);
+        self.rebuild_index(&lib);
+
+        Ok(())
+    }
+
+    /// Checks whether a library registers names or aliases that another loaded
+    /// library already registered, and applies the configured conflict policy.
+    ///
+    /// Every conflict is logged, whichever library ends up with the name.
+    fn resolve_conflicts(
+        &self,
+        lib: &HashMap<String, Arc<CommandRegistrar>>,
+        registrar: &mut CommandRegistrar,
+    ) -> Result<(), ProcessorError> {
+        let outcome = match self.config.conflict_policy {
+            ConflictPolicy::Reject => "refusing to load the library",
+            ConflictPolicy::Skip => "skipping it",
+        };
+        let mut conflicts = Vec::new();
+        for (name, command) in &registrar.commands {
+            let existing = lib
+                .iter()
+                .filter(|(library, _)| *library != &registrar.library_name)
+                .find_map(|(library, existing)| existing.commands.get(name).map(|existing| (library, existing)));
+            if let Some((existing_library, existing_command)) = existing {
+                warn!(
+                    name = name.as_str(), library = registrar.library_name.as_str(), existing_library = existing_library.as_str();
+                    "{} of library {} shadows {} of library {}, {}",
+                    describe_registration(name, command), registrar.library_name,
+                    describe_registration(name, existing_command), existing_library, outcome
+                );
+                conflicts.push((name.clone(), existing_library.clone()));
+            }
+        }
+
+        for (name, existing_library) in conflicts {
+            match self.config.conflict_policy {
+                ConflictPolicy::Reject => {
+                    return Err(ProcessorError::CommandConflict {
+                        command: name,
+                        existing_library,
+                        new_library: registrar.library_name.clone(),
+                    });
+                }
+                ConflictPolicy::Skip => skip_registration(registrar, &name),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replace a loaded library with the current build on disk.
+    ///
+    /// The new build is loaded before the old one is closed, so the previous
+    /// version stays active if the reload fails. This also means `on_load` of the
+    /// new build runs before `on_unload` of the previous one. Libraries that
+    /// aren't loaded yet are simply loaded.
+    ///
+    /// # Safety
+    ///
+    /// See [`CommandProcessor::load`].
+    pub unsafe fn reload<P: AsRef<Path>>(&self, library_path: P) -> Result<(), ProcessorError> {
+        let path = library_path.as_ref();
+        let result = self.try_reload(path);
+        match &result {
+            Ok(true) => self.publish_load_result(path.as_os_str(), Ok(()), LibraryEvent::Reloaded),
+            Ok(false) => self.publish_load_result(path.as_os_str(), Ok(()), LibraryEvent::Loaded),
+            Err(err) => self.publish_load_result(path.as_os_str(), Err(err), LibraryEvent::Loaded),
+        }
+        result.map(|_| ())
+    }
+
+    /// Returns whether a loaded library was replaced, rather than loaded for the first time
+    unsafe fn try_reload(&self, path: &Path) -> Result<bool, ProcessorError> {
+        let file_name = path.file_name().unwrap().to_str().unwrap().to_string();
+        let is_loaded = self.libraries.read().contains_key(&file_name);
+        if !is_loaded {
+            return self.try_load(path).map(|_| false);
+        }
+
+        let staged = stage_library(path, &file_name)?;
+        let registrar = self.open(&staged, path.to_path_buf());
+        // Once the library is mapped (or failed to load) the staged copy isn't needed anymore
+        let remove_result = std::fs::remove_file(&staged);
+        if remove_result.is_err() {
+            debug!(
+                "Unable to remove staged copy {} while it's open, it's removed on the next start: {}",
+                staged.display(),
+                remove_result.err().unwrap()
+            );
+        }
+        let mut registrar = registrar?;
+
+        let previous = {
+            let mut lib = self.libraries.write();
+            let conflicts = self.resolve_conflicts(&lib, &mut registrar);
+            if conflicts.is_err() {
+                registrar.run_unload_hook();
+                return Err(conflicts.err().unwrap());
+            }
+            let registrar = Arc::new(registrar);
+            // Replaces the tasks of the previous build
+            self.start_library(&registrar);
+            let previous = lib.insert(file_name.clone(), registrar);
+            self.rebuild_index(&lib);
+            previous
+        };
+        if let Some(previous) = previous {
+            if self.close(&file_name, previous).is_err() {
+                warn!("The previous build of {} will be closed once it's no longer in use", file_name);
+            } else {
+                self.check_closed(&file_name, 1);
+            }
+        }
+
+        info!("Reloaded library: {}", file_name);
+        Ok(true)
+    }
+
+    /// Creates the cancellation token of a library and spawns its periodic
+    /// tasks. The token of a previous library of the same name is cancelled.
+    ///
+    /// Tasks get their own clones of the platform and userservice clients, just
+    /// like the commands do.
+    fn start_library(&self, registrar: &Arc<CommandRegistrar>) {
+        let shutdown = self.shutdown.child_token();
+        let previous = self
+            .library_shutdowns
+            .lock()
+            .insert(registrar.library_name.clone(), shutdown.clone());
+        if let Some(previous) = previous {
+            previous.cancel();
+        }
+
+        for (index, task) in registrar.tasks.iter().enumerate() {
+            debug!("Starting task {} of library {} every {:?}", task.name, registrar.library_name, task.interval);
+            let span = tracing::info_span!("task", task = task.name.as_str(), library = registrar.library_name.as_str());
+            self.runtime.spawn(
+                run_task(
+                    Arc::downgrade(registrar),
+                    index,
+                    task.interval,
+                    self.sender.clone(),
+                    self.userservice_client.clone(),
+                    shutdown.clone(),
+                )
+                .instrument(span),
             );
-            error!("Unlike all of the other errors that occur, this one will prevent the commands from this library to run in order to prevent the service from panicking!");
         }
+    }
 
-        registrar.commands.clear();
+    /// Stops the periodic tasks of a library and asks its running commands to stop.
+    ///
+    /// A task that is running right now is dropped the next time it's polled,
+    /// until then it keeps its library from being closed.
+    fn stop_library(&self, library_name: &str) {
+        if let Some(shutdown) = self.library_shutdowns.lock().remove(library_name) {
+            shutdown.cancel();
+        }
     }
 
-    /// Load a plugin library and add all contained functions to the internal
-    /// function table.
+    /// Returns the token that is cancelled once the library is unloaded or the service shuts down
+    fn library_cancellation(&self, library_name: &str) -> CancellationToken {
+        self.library_shutdowns
+            .lock()
+            .get(library_name)
+            .cloned()
+            .unwrap_or_else(|| {
+                // The library is being unloaded
+                let cancelled = CancellationToken::new();
+                cancelled.cancel();
+                cancelled
+            })
+    }
+
+    /// Broadcasts the outcome of loading a library, `event` builds the event for a successful load
+    fn publish_load_result<F: FnOnce(String) -> LibraryEvent>(&self, library_path: &OsStr, result: Result<(), &ProcessorError>, event: F) {
+        let library_name = Path::new(library_path)
+            .file_name()
+            .map_or_else(|| library_path.to_string_lossy(), OsStr::to_string_lossy)
+            .to_string();
+        let event = match result {
+            Ok(_) => event(library_name),
+            // Loading a library that's already loaded doesn't change anything
+            Err(ProcessorError::AlreadyLoaded { .. }) => return,
+            Err(err) => LibraryEvent::LoadFailed {
+                library_name,
+                message: err.to_string(),
+            },
+        };
+        // Sending only fails if nobody is subscribed
+        let _ = self.library_events.send(event);
+    }
+
+    /// Returns a receiver for all command invocations from now on
+    pub fn subscribe_invocations(&self) -> broadcast::Receiver<InvocationEvent> {
+        self.invocation_events.subscribe()
+    }
+
+    /// Returns a receiver for all library events from now on
+    pub fn subscribe_library_events(&self) -> broadcast::Receiver<LibraryEvent> {
+        self.library_events.subscribe()
+    }
+
+    /// Reload a library only if the file on disk differs from the loaded build.
+    ///
+    /// A file with the name of a loaded library that was loaded from another
+    /// path, like the same library in a later commands directory, is shadowed
+    /// by the loaded library and ignored. Returns whether the library was
+    /// (re)loaded.
     ///
     /// # Safety
     ///
-    /// A plugin library **must** be implemented using the
-    /// [`bpp_command_api::export_command!()`] macro. Trying manually implement
-    /// a plugin without going through that macro will result in undefined
-    /// behavior.
-    pub unsafe fn load<P: AsRef<OsStr>>(&self, library_path: P) -> Result<(), ProcessorError> {
-        let path: PathBuf = library_path.as_ref().into();
+    /// See [`CommandProcessor::load`].
+    pub unsafe fn reload_if_changed<P: AsRef<Path>>(&self, library_path: P) -> Result<bool, ProcessorError> {
+        let path = library_path.as_ref();
         let file_name = path.file_name().unwrap().to_str().unwrap().to_string();
+        let loaded = self
+            .libraries
+            .read()
+            .get(&file_name)
+            .map(|registrar| (registrar.library_path.clone(), registrar.checksum.clone()));
+
+        if let Some((loaded_path, loaded_checksum)) = loaded {
+            if !same_file(&loaded_path, path) {
+                debug!("Library {} is shadowed by {}, skipping reload", path.display(), loaded_path.display());
+                return Ok(false);
+            }
+            if checksum(path, &file_name)? == loaded_checksum {
+                debug!("Library {} didn't change, skipping reload", file_name);
+                return Ok(false);
+            }
+        }
+
+        self.reload(path)?;
+        Ok(true)
+    }
+
+    /// Watch a directory and hot-reload libraries that get created or modified in it.
+    ///
+    /// Filesystem events are debounced by `debounce`, so a library that is still
+    /// being copied into place is only loaded once writing has settled. Changes
+    /// to the configuration file of a loaded library reload the library.
+    pub fn watch_directory<P: AsRef<Path>>(self: &Arc<Self>, path: P, debounce: Duration) -> Result<(), ProcessorError> {
+        let path = path.as_ref();
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        let watcher = notify::watcher(tx, debounce);
+        if watcher.is_err() {
+            return Err(ProcessorError::WatchError {
+                path: path.display().to_string(),
+                message: watcher.err().unwrap().to_string(),
+            });
+        }
+        let mut watcher = watcher.unwrap();
+
+        let watch_result = watcher.watch(path, RecursiveMode::NonRecursive);
+        if watch_result.is_err() {
+            return Err(ProcessorError::WatchError {
+                path: path.display().to_string(),
+                message: watch_result.err().unwrap().to_string(),
+            });
+        }
+
+        let processor = Arc::clone(self);
+        std::thread::spawn(move || {
+            // Dropping the watcher stops the events, so it has to live as long as this thread
+            let _watcher = watcher;
+            for event in rx {
+                let changed = match event {
+                    DebouncedEvent::Create(path) | DebouncedEvent::Write(path) | DebouncedEvent::Rename(_, path) => path,
+                    DebouncedEvent::Error(err, path) => {
+                        warn!("Error while watching {:?}: {}", path, err);
+                        continue;
+                    }
+                    _ => continue,
+                };
+                // The library itself didn't change, so it has to be reloaded unconditionally
+                if let Some(library_path) = processor.library_for_config(&changed) {
+                    info!("Configuration changed on disk: {}", changed.display());
+                    let reload_result = unsafe { processor.reload(&library_path) };
+                    if reload_result.is_err() {
+                        error!("Error reloading library: {}", reload_result.err().unwrap());
+                    }
+                    continue;
+                }
+                if !is_library(&changed) {
+                    continue;
+                }
+
+                info!("Library changed on disk: {}", changed.display());
+                let reload_result = unsafe { processor.reload_if_changed(&changed) };
+                if reload_result.is_err() {
+                    error!("Error reloading library: {}", reload_result.err().unwrap());
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Returns the path of the loaded library a configuration file belongs to
+    fn library_for_config(&self, path: &Path) -> Option<PathBuf> {
+        if is_library(path) {
+            return None;
+        }
+        self.libraries
+            .read()
+            .values()
+            .map(|registrar| &registrar.library_path)
+            .find(|library_path| same_file(&config_path(library_path), path))
+            .cloned()
+    }
+
+    /// Returns the path a loaded library was originally loaded from
+    pub fn library_path<S: AsRef<str>>(&self, library_name: S) -> Option<PathBuf> {
+        let lib = self.libraries.read();
+        lib.get(library_name.as_ref())
+            .map(|registrar| registrar.library_path.clone())
+    }
+
+    /// Open a library and let it register its commands, without adding them to
+    /// the command table.
+    ///
+    /// `source_path` is the path the library was deployed to, which might differ
+    /// from the path it's actually opened from when it got staged for a reload.
+    unsafe fn open<P: AsRef<OsStr>>(&self, library_path: P, source_path: PathBuf) -> Result<CommandRegistrar, ProcessorError> {
+        let file_name = source_path.file_name().unwrap().to_str().unwrap().to_string();
+        let checksum = checksum(library_path.as_ref(), &file_name)?;
         let library = Library::new(library_path);
 
         if library.is_err() {
@@ -298,6 +2382,7 @@ impl CommandProcessor {
         }
         let library = library.unwrap();
         let library_arc = Arc::new(library);
+        self.track_open(&file_name, &library_arc);
 
         let decl = library_arc
             .get::<*mut CommandDeclaration>(b"command_declaration\0")
@@ -312,60 +2397,224 @@ impl CommandProcessor {
             });
         }
 
-        if decl.core_version != bpp_command_api::CORE_VERSION {
+        // Without a configured requirement, the core versions have to match exactly
+        let (core_version_matches, expected_core_version) = match &self.config.core_version_req {
+            Some(requirement) => (
+                semver::Version::parse(decl.core_version)
+                    .map_or(false, |version| requirement.matches(&version)),
+                requirement.to_string(),
+            ),
+            None => (
+                decl.core_version == bpp_command_api::CORE_VERSION,
+                bpp_command_api::CORE_VERSION.to_string(),
+            ),
+        };
+        if !core_version_matches {
             return Err(ProcessorError::LibraryCoreVersionMismatch {
                 library_name: file_name,
-                core_version: bpp_command_api::CORE_VERSION.to_string(),
+                core_version: expected_core_version,
                 actual_core_version: decl.core_version.to_string(),
             });
         }
 
-        let mut registrar = CommandRegistrar::new(Arc::clone(&library_arc), file_name.clone());
+        let config = read_config(&source_path);
+        let mut registrar = CommandRegistrar::new(Arc::clone(&library_arc), file_name, source_path, checksum, config).with_declaration(&decl);
+        if let Some(on_load) = decl.on_load {
+            let context = registrar.context();
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| on_load(&context)));
+            let result = result.unwrap_or_else(|_| Err("on_load panicked".to_string()));
+            if result.is_err() {
+                return Err(ProcessorError::LoadHookFailed {
+                    library_name: context.library_name,
+                    message: result.err().unwrap(),
+                });
+            }
+        }
         (decl.register)(&mut registrar);
-        let lib_clone = self.libraries.clone();
-        let mut lib = lib_clone.lock().unwrap();
-        lib
-            .insert(file_name, Arc::new(registrar));
+        self.check_collisions(&mut registrar)?;
+
+        Ok(registrar)
+    }
+
+    /// Remembers an opened library, to tell later whether it was closed again
+    fn track_open(&self, library_name: &str, library: &Arc<Library>) {
+        self.opened.lock().push((library_name.to_string(), Arc::downgrade(library)));
+        // Closed libraries are only counted down once the open libraries are listed
+        self.metrics.open_libraries.inc();
+    }
 
+    /// Applies the conflict policy to names a library registered more than once
+    fn check_collisions(&self, registrar: &mut CommandRegistrar) -> Result<(), ProcessorError> {
+        if self.config.conflict_policy == ConflictPolicy::Reject && !registrar.collisions.is_empty() {
+            registrar.run_unload_hook();
+            let (name, _) = registrar.collisions.swap_remove(0);
+            return Err(ProcessorError::CommandConflict {
+                command: name,
+                existing_library: registrar.library_name.clone(),
+                new_library: registrar.library_name.clone(),
+            });
+        }
+        for (name, existing_command) in &registrar.collisions {
+            warn!(
+                name = name.as_str(), library = registrar.library_name.as_str(), existing_library = registrar.library_name.as_str();
+                "{} is already registered by command {} of library {}, skipping the later registration",
+                name, existing_command, registrar.library_name
+            );
+        }
         Ok(())
     }
 }
 
 pub struct CommandServiceServer {
-    pub processor: Arc<CommandProcessor>
+    pub processor: Arc<CommandProcessor>,
+    pub commands_directories: Vec<PathBuf>,
+}
+
+impl CommandServiceServer {
+    /// Resolves a library path requested over gRPC, making sure it points to a
+    /// library inside one of the commands directories.
+    ///
+    /// Relative paths are resolved against the commands directories in order.
+    fn resolve_library_path(&self, path: &str) -> Result<PathBuf, ProcessorError> {
+        for commands_directory in &self.commands_directories {
+            let commands_directory = commands_directory.canonicalize();
+            if commands_directory.is_err() {
+                continue;
+            }
+            let commands_directory = commands_directory.unwrap();
+
+            let library_path = commands_directory.join(path).canonicalize();
+            if library_path.is_err() {
+                continue;
+            }
+            let library_path = library_path.unwrap();
+
+            if library_path.starts_with(&commands_directory)
+                && library_path.is_file()
+                && is_library(&library_path)
+            {
+                return Ok(library_path);
+            }
+        }
+
+        Err(ProcessorError::InvalidLibraryPath {
+            path: path.to_string(),
+            message: "path is not a library inside of the commands directories".to_string(),
+        })
+    }
+}
+
+type EventStream<T> = Pin<Box<dyn Stream<Item = Result<T, tonic::Status>> + Send + Sync>>;
+
+/// Forwards broadcast events to a gRPC stream until the processor goes away.
+///
+/// Subscribers that fall behind skip the events they missed instead of
+/// holding up the sender.
+fn event_stream<E, T>(mut receiver: broadcast::Receiver<E>) -> EventStream<T>
+where
+    E: Clone + Send + 'static,
+    T: From<E> + Send + Sync + 'static,
+{
+    Box::pin(async_stream::stream! {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => yield Ok(T::from(event)),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("Event subscriber fell behind, skipped {} events", skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+/// Converts a Unix timestamp in milliseconds, as kept by the stats
+fn timestamp_from_millis(millis: i64) -> prost_types::Timestamp {
+    prost_types::Timestamp {
+        seconds: millis / 1000,
+        nanos: (millis % 1000) as i32 * 1_000_000,
+    }
+}
+
+/// Maps a processor error to the gRPC status that best describes it, keeping
+/// the error's message
+impl From<ProcessorError> for tonic::Status {
+    fn from(err: ProcessorError) -> Self {
+        let message = err.to_string();
+        match err {
+            ProcessorError::CommandNotFound { .. } | ProcessorError::LibraryNotFound { .. } => {
+                tonic::Status::not_found(message)
+            }
+            ProcessorError::PermissionDenied { .. } => tonic::Status::permission_denied(message),
+            ProcessorError::Timeout { .. } => tonic::Status::deadline_exceeded(message),
+            ProcessorError::Cooldown { .. }
+            | ProcessorError::RateLimited { .. } => tonic::Status::resource_exhausted(message),
+            ProcessorError::LibraryRustCVersionMismatch { .. }
+            | ProcessorError::LibraryCoreVersionMismatch { .. }
+            | ProcessorError::LibraryInUse { .. }
+            | ProcessorError::CircuitOpen { .. }
+            | ProcessorError::Disabled { .. } => tonic::Status::failed_precondition(message),
+            ProcessorError::InvalidLibraryPath { .. } => tonic::Status::invalid_argument(message),
+            ProcessorError::AlreadyLoaded { .. } | ProcessorError::CommandConflict { .. } => {
+                tonic::Status::already_exists(message)
+            }
+            ProcessorError::CommandExecutionFailed { .. }
+            | ProcessorError::CommandPanicked { .. }
+            | ProcessorError::LoadError { .. }
+            | ProcessorError::LoadHookFailed { .. }
+            | ProcessorError::WatchError { .. } => tonic::Status::internal(message),
+        }
+    }
 }
 
 #[async_trait]
 impl super::command_service_server::CommandService for CommandServiceServer {
+    type SubscribeLibraryEventsStream = EventStream<crate::commandservice::LibraryEvent>;
+    type SubscribeInvocationsStream = EventStream<crate::commandservice::InvocationEvent>;
+
     async fn get_commands(
         &self,
-        _: tonic::Request<()>,
+        request: tonic::Request<crate::commandservice::CommandListRequest>,
     ) -> Result<tonic::Response<crate::commandservice::CommandList>, tonic::Status> {
-        info!("Getting commands");
+        let request = request.into_inner();
+        let name_contains = request.name_contains.to_lowercase();
         let mut commands: Vec<super::commandservice::Command> = Vec::new();
         let lib_clone = self.processor.libraries.clone();
-        info!("Acquiring mutex lock");
-        let lib = lib_clone.lock().unwrap();
-        info!("Iterating over libraries");
+        let lib = lib_clone.read();
         for (library, registrar) in lib.iter() {
-            for (name, command) in &registrar.commands {
-                if command.is_alias {
+            if !request.library.is_empty() && library != &request.library {
+                continue;
+            }
+            for command in registrar.commands.values() {
+                if command.is_alias || !command.name.to_lowercase().contains(&name_contains) {
                     continue;
                 }
-                let command_name = name.clone();
-                let cmd = super::commandservice::Command {
-                    name: command_name,
-                    aliases: command.aliases.clone(),
-                    description: "A command for ByersPlusPlus".to_string(),
-                    library: library.clone(),
-                };
-                commands.push(cmd);
+                commands.push(command.to_proto(library, self.processor.is_enabled(&command.name)));
             }
         }
+        // Pages have to be stable between requests, the libraries are kept in a hash map
+        commands.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let total = commands.len() as u32;
+        if request.page_size > 0 {
+            commands = commands
+                .into_iter()
+                .skip(request.page as usize * request.page_size as usize)
+                .take(request.page_size as usize)
+                .collect();
+        }
 
-        info!("Sending response");
-        let count = lib.len() as i32;
-        let command_list = super::commandservice::CommandList { commands, count };
+        // Dashboards poll this, so it's not worth more than a debug line
+        debug!(
+            "Returning {} of {} commands from {} libraries (page {}, page size {})",
+            commands.len(), total, lib.len(), request.page, request.page_size
+        );
+        let command_list = super::commandservice::CommandList {
+            count: commands.len() as i32,
+            commands,
+            total,
+            library_count: lib.len() as u32,
+        };
         return Ok(tonic::Response::new(command_list));
     }
 
@@ -374,35 +2623,539 @@ impl super::command_service_server::CommandService for CommandServiceServer {
         request: tonic::Request<prost::alloc::string::String>,
     ) -> Result<tonic::Response<crate::commandservice::Command>, tonic::Status> {
         let lib_clone = self.processor.libraries.clone();
-        let lib = lib_clone.lock().unwrap();
-        let command_name = request.into_inner();
-        let mut found = false;
-        let mut found_command = None;
-        for (library, registrar) in lib.iter() {
-            for (name, command) in &registrar.commands {
-                if command.is_alias {
-                    continue;
+        let lib = lib_clone.read();
+        let requested_name = request.into_inner();
+        let command_name = requested_name.to_lowercase();
+
+        // Primary names take precedence, aliases are resolved to the command they belong to
+        let found = lib
+            .iter()
+            .find_map(|(library, registrar)| {
+                registrar
+                    .commands
+                    .get(&command_name)
+                    .filter(|command| !command.is_alias)
+                    .map(|command| (library, command))
+            })
+            .or_else(|| {
+                lib.iter().find_map(|(library, registrar)| {
+                    registrar
+                        .commands
+                        .get(&command_name)
+                        .filter(|command| command.is_alias)
+                        .and_then(|alias| registrar.commands.get(&alias.name.to_lowercase()))
+                        .map(|command| (library, command))
+                })
+            });
+        let found_command = found.map(|(library, command)| {
+            command.to_proto(library, self.processor.is_enabled(&command.name))
+        });
+
+        if found_command.is_none() {
+            return Err(ProcessorError::CommandNotFound { command: requested_name }.into());
+        }
+        let found_command = found_command.unwrap();
+        return Ok(tonic::Response::new(found_command));
+    }
+
+    async fn reload_library(
+        &self,
+        request: tonic::Request<crate::commandservice::LibraryName>,
+    ) -> Result<tonic::Response<crate::commandservice::ReloadResult>, tonic::Status> {
+        let library_name = request.into_inner().name;
+        info!("Reload of library {} requested", library_name);
+
+        let library_path = self.processor.library_path(&library_name);
+        if library_path.is_none() {
+            return Ok(tonic::Response::new(super::commandservice::ReloadResult {
+                success: false,
+                message: format!("Library {} is not loaded", library_name),
+            }));
+        }
+        let library_path = library_path.unwrap();
+
+        // Unloading a library that can't be loaded again would leave its commands unavailable
+        if !library_path.is_file() {
+            return Ok(tonic::Response::new(super::commandservice::ReloadResult {
+                success: false,
+                message: format!("Library file {} does not exist", library_path.display()),
+            }));
+        }
+
+        let processor = self.processor.clone();
+        let reload_result = tokio::task::spawn_blocking(move || unsafe {
+            processor.reload(&library_path)
+        })
+        .await
+        .map_err(|err| tonic::Status::internal(err.to_string()))?;
+
+        if reload_result.is_err() {
+            let err = reload_result.err().unwrap();
+            error!("{}", err);
+            return Ok(tonic::Response::new(super::commandservice::ReloadResult {
+                success: false,
+                message: err.to_string(),
+            }));
+        }
+
+        Ok(tonic::Response::new(super::commandservice::ReloadResult {
+            success: true,
+            message: format!("Library {} reloaded", library_name),
+        }))
+    }
+
+    async fn ping(
+        &self,
+        _: tonic::Request<()>,
+    ) -> Result<tonic::Response<crate::commandservice::PingResponse>, tonic::Status> {
+        let readiness = self.processor.readiness.get();
+        Ok(tonic::Response::new(super::commandservice::PingResponse {
+            uptime_secs: self.processor.started_at.elapsed().as_secs(),
+            loaded_libraries: self.processor.libraries.read().len() as u32,
+            open_libraries: self.processor.open_libraries().len() as u32,
+            ready: readiness.is_ready(),
+            not_ready_reasons: readiness.failing().into_iter().map(String::from).collect(),
+        }))
+    }
+
+    async fn get_libraries(
+        &self,
+        _: tonic::Request<()>,
+    ) -> Result<tonic::Response<crate::commandservice::LibraryList>, tonic::Status> {
+        let lib = self.processor.libraries.read();
+        let libraries = lib
+            .values()
+            .map(|registrar| {
+                let alias_count = registrar
+                    .commands
+                    .values()
+                    .filter(|command| command.is_alias)
+                    .count();
+                super::commandservice::Library {
+                    name: registrar.library_name.clone(),
+                    command_count: (registrar.commands.len() - alias_count) as u32,
+                    alias_count: alias_count as u32,
+                    rustc_version: registrar.rustc_version.clone(),
+                    core_version: registrar.core_version.clone(),
                 }
-                if name == &command_name {
-                    found = true;
-                    found_command = Some(super::commandservice::Command {
-                        name: command_name.clone(),
-                        aliases: command.aliases.clone(),
-                        description: "A command for ByersPlusPlus".to_string(),
-                        library: library.clone(),
-                    });
-                    break;
+            })
+            .collect();
+
+        Ok(tonic::Response::new(super::commandservice::LibraryList { libraries }))
+    }
+
+    async fn get_command_stats(
+        &self,
+        _: tonic::Request<()>,
+    ) -> Result<tonic::Response<crate::commandservice::CommandStatsList>, tonic::Status> {
+        let index = self.processor.index.read();
+        let stats = self
+            .processor
+            .stats
+            .snapshot()
+            .into_iter()
+            .map(|(name, stats)| {
+                let last_invoked = stats.last_invoked.load(Ordering::Relaxed);
+                let last_error = stats.last_error.lock().clone();
+                // Commands of unloaded libraries keep their stats, but have no breaker anymore
+                let (consecutive_failures, disabled_for) = index
+                    .get(&name.to_lowercase())
+                    .map_or((0, None), |command| command.breaker.snapshot());
+                let quota = self.processor.quotas.quota(&name);
+                super::commandservice::CommandStats {
+                    invocations: stats.invocations.load(Ordering::Relaxed),
+                    successes: stats.successes.load(Ordering::Relaxed),
+                    failures: stats.failures.load(Ordering::Relaxed),
+                    last_invoked: (last_invoked > 0).then(|| timestamp_from_millis(last_invoked)),
+                    consecutive_failures,
+                    disabled: disabled_for.is_some(),
+                    disabled_for_secs: disabled_for.map_or(0, |remaining| remaining.as_secs()),
+                    last_error: last_error.as_ref().map(|error| error.message.clone()).unwrap_or_default(),
+                    last_error_at: last_error.map(|error| timestamp_from_millis(error.at)),
+                    quota_limit: quota.map_or(0, |quota| quota.limit),
+                    quota_window_secs: quota.map_or(0, |quota| quota.window.as_secs()),
+                    quota_usage: self.processor.quotas.usage(&name).into_iter().collect(),
+                    name,
                 }
+            })
+            .collect();
+        drop(index);
+
+        Ok(tonic::Response::new(super::commandservice::CommandStatsList { stats }))
+    }
+
+    async fn load_library(
+        &self,
+        request: tonic::Request<crate::commandservice::LibraryPath>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let library_path = self.resolve_library_path(&request.into_inner().path)?;
+        info!("Loading library: {}", library_path.display());
+
+        let processor = self.processor.clone();
+        tokio::task::spawn_blocking(move || unsafe { processor.load(&library_path) })
+            .await
+            .map_err(|err| tonic::Status::internal(err.to_string()))??;
+
+        Ok(tonic::Response::new(()))
+    }
+
+    async fn unload_library(
+        &self,
+        request: tonic::Request<crate::commandservice::LibraryName>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let library_name = request.into_inner().name;
+        info!("Unloading library: {}", library_name);
+
+        self.processor.unload(&library_name).await?;
+
+        Ok(tonic::Response::new(()))
+    }
+
+    async fn force_unload_library(
+        &self,
+        request: tonic::Request<crate::commandservice::LibraryName>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let library_name = request.into_inner().name;
+        info!("Force unloading library: {}", library_name);
+
+        self.processor.force_unload(&library_name).await?;
+
+        Ok(tonic::Response::new(()))
+    }
+
+    async fn set_command_enabled(
+        &self,
+        request: tonic::Request<crate::commandservice::CommandToggle>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let toggle = request.into_inner();
+        info!("Setting command {} enabled: {}", toggle.name, toggle.enabled);
+
+        self.processor.set_enabled(&toggle.name, toggle.enabled).await?;
+
+        Ok(tonic::Response::new(()))
+    }
+
+    async fn set_command_cooldown(
+        &self,
+        request: tonic::Request<crate::commandservice::CommandCooldown>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let cooldown = request.into_inner();
+        info!("Setting cooldown of command {} to {} seconds", cooldown.name, cooldown.seconds);
+
+        self.processor
+            .set_cooldown(&cooldown.name, Duration::from_secs(cooldown.seconds))?;
+
+        Ok(tonic::Response::new(()))
+    }
+
+    async fn subscribe_library_events(
+        &self,
+        _: tonic::Request<()>,
+    ) -> Result<tonic::Response<Self::SubscribeLibraryEventsStream>, tonic::Status> {
+        let receiver = self.processor.subscribe_library_events();
+        Ok(tonic::Response::new(event_stream(receiver)))
+    }
+
+    async fn subscribe_invocations(
+        &self,
+        _: tonic::Request<()>,
+    ) -> Result<tonic::Response<Self::SubscribeInvocationsStream>, tonic::Status> {
+        let receiver = self.processor.subscribe_invocations();
+        Ok(tonic::Response::new(event_stream(receiver)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bpp_command_api::userservice::Role;
+    use crate::testing::{self, Echo, FakeChat, FakeUsers, Panics};
+
+    #[test]
+    fn gating_errors_describe_themselves() {
+        let command = || "roll".to_string();
+        assert_eq!(
+            ProcessorError::Timeout { command: command(), timeout_secs: 5 }.to_string(),
+            "Command roll did not finish within 5 seconds"
+        );
+        assert_eq!(
+            ProcessorError::PermissionDenied { command: command() }.to_string(),
+            "Insufficient permissions to run command roll"
+        );
+        assert_eq!(
+            ProcessorError::Cooldown { command: command(), remaining_secs: 3 }.to_string(),
+            "Command roll is on cooldown for another 3 seconds"
+        );
+        assert_eq!(
+            ProcessorError::RateLimited {
+                channel_id: "UC_test".to_string(),
+                reason: "is sending commands too fast".to_string(),
             }
-            if found {
-                break;
+            .to_string(),
+            "User UC_test is sending commands too fast"
+        );
+        assert_eq!(ProcessorError::Disabled { command: command() }.to_string(), "Command roll is disabled");
+    }
+
+    async fn call(processor: &Arc<CommandProcessor<FakeChat, FakeUsers>>, text: &str) -> Result<Option<String>, ProcessorError> {
+        processor
+            .call_with(testing::message(text), &mut FakeChat::default(), &mut FakeUsers::default())
+            .await
+    }
+
+    #[test]
+    fn recognizes_staged_copies() {
+        let staged = format!("mylib.{}.1234.{}", DLL_EXTENSION, DLL_EXTENSION);
+        assert!(is_staged_copy(&staged));
+        assert!(!is_staged_copy(&format!("mylib.{}", DLL_EXTENSION)));
+        assert!(!is_staged_copy(&format!("mylib.1234.{}", DLL_EXTENSION)));
+        assert!(!is_staged_copy(&format!("mylib.{}.v2.{}", DLL_EXTENSION, DLL_EXTENSION)));
+    }
+
+    #[tokio::test]
+    async fn unknown_commands_are_not_found() {
+        let processor = testing::processor(ProcessorConfig::default());
+        let result = call(&processor, "!nope").await;
+        assert!(matches!(result, Err(ProcessorError::CommandNotFound { command }) if command == "nope"));
+    }
+
+    #[tokio::test]
+    async fn help_answers_without_libraries() {
+        let processor = testing::processor(ProcessorConfig::default());
+        let result = call(&processor, "!help nope").await;
+        assert_eq!(result.unwrap().as_deref(), Some("Unknown command nope"));
+    }
+
+    #[tokio::test]
+    async fn help_hides_disabled_commands() {
+        let processor = testing::processor(ProcessorConfig::default());
+        processor
+            .load_in_process("fake", |registrar| registrar.register_command("echo", &["e"], Box::new(Echo)))
+            .unwrap();
+        assert_eq!(
+            call(&processor, "!help e").await.unwrap().as_deref(),
+            Some("!echo: A command for ByersPlusPlus (aliases: !e)")
+        );
+
+        processor.set_enabled("echo", false).await.unwrap();
+        assert_eq!(call(&processor, "!help").await.unwrap().as_deref(), Some("No commands available"));
+        assert_eq!(call(&processor, "!help e").await.unwrap().as_deref(), Some("Unknown command e"));
+    }
+
+    #[tokio::test]
+    async fn command_names_ignore_case() {
+        let processor = testing::processor(ProcessorConfig::default());
+        processor
+            .load_in_process("fake", |registrar| registrar.register_command("Foo", &[], Box::new(Echo)))
+            .unwrap();
+
+        assert_eq!(call(&processor, "!foo bar").await.unwrap().as_deref(), Some("bar"));
+        assert_eq!(call(&processor, "!FOO bar").await.unwrap().as_deref(), Some("bar"));
+        // The registered casing is kept for display
+        let names: Vec<String> = processor.commands().into_iter().map(|(_, command)| command.name).collect();
+        assert_eq!(names, vec!["Foo"]);
+    }
+
+    #[tokio::test]
+    async fn admin_commands_follow_the_user_role() {
+        let processor = testing::processor(ProcessorConfig {
+            admin_commands: true,
+            ..ProcessorConfig::default()
+        });
+        // Users the userservice has no role for keep the default of the message
+        let call_as = |role: Option<Role>| {
+            let processor = processor.clone();
+            let mut message = testing::message("!libraries");
+            if let Some(role) = role {
+                message.user.role = role as i32;
             }
-        }
+            async move {
+                processor
+                    .call_with(message, &mut FakeChat::default(), &mut FakeUsers::default())
+                    .await
+            }
+        };
 
-        if !found || found_command.is_none() {
-            return Err(tonic::Status::not_found(format!("")));
+        let result = call_as(None).await;
+        assert!(matches!(result, Err(ProcessorError::PermissionDenied { command }) if command == "libraries"));
+        assert!(call_as(Some(Role::Moderator)).await.is_ok());
+        assert!(call_as(Some(Role::Owner)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn used_up_quotas_tell_when_they_reset() {
+        let processor = testing::processor(ProcessorConfig {
+            quotas: "echo=1/1d".parse().unwrap(),
+            ..ProcessorConfig::default()
+        });
+        processor
+            .load_in_process("fake", |registrar| registrar.register_command("echo", &[], Box::new(Echo)))
+            .unwrap();
+
+        assert_eq!(call(&processor, "!echo hi").await.unwrap().as_deref(), Some("hi"));
+        let result = call(&processor, "!echo hi").await;
+        assert!(matches!(
+            result,
+            Err(ProcessorError::RateLimited { channel_id, reason })
+                if channel_id == testing::CHANNEL_ID && reason.starts_with("used up the quota of command echo, it resets in ")
+        ));
+    }
+
+    #[tokio::test]
+    async fn panicking_commands_are_caught() {
+        let processor = testing::processor(ProcessorConfig::default());
+        processor
+            .load_in_process("fake", |registrar| {
+                registrar.register_command("boom", &[], Box::new(Panics));
+                registrar.register_command("echo", &[], Box::new(Echo));
+            })
+            .unwrap();
+
+        let result = call(&processor, "!boom").await;
+        assert!(matches!(result, Err(ProcessorError::CommandPanicked { command, .. }) if command == "boom"));
+        // Later messages are still processed
+        assert_eq!(call(&processor, "!echo hi").await.unwrap().as_deref(), Some("hi"));
+    }
+
+    #[tokio::test]
+    async fn index_finds_commands_of_every_library() {
+        let processor = testing::processor(ProcessorConfig::default());
+        for library in 0..50 {
+            processor
+                .load_in_process(&format!("lib{}", library), |registrar| {
+                    for command in 0..10 {
+                        registrar.register_command(&format!("cmd{}_{}", library, command), &[], Box::new(Echo));
+                    }
+                })
+                .unwrap();
         }
-        let found_command = found_command.unwrap();
-        return Ok(tonic::Response::new(found_command));
+
+        assert_eq!(processor.index.read().len(), 500);
+        let command = processor.lookup("cmd42_7").unwrap();
+        assert_eq!((command.name.as_str(), command._lib_name.as_str()), ("cmd42_7", "lib42"));
+        assert!(processor.lookup("lib42::cmd42_7").is_some());
+        assert!(processor.lookup("lib41::cmd42_7").is_none());
+
+        processor.unload("lib42").await.unwrap();
+        assert!(processor.lookup("cmd42_7").is_none());
+        assert_eq!(processor.index.read().len(), 490);
+    }
+
+    #[tokio::test]
+    async fn overlapping_aliases_are_deduplicated() {
+        let processor = testing::processor(ProcessorConfig::default());
+        processor
+            .load_in_process("fake", |registrar| {
+                registrar.register_command("roll", &["r", "R", "Roll", "r", "dice"], Box::new(Echo))
+            })
+            .unwrap();
+
+        let commands = processor.commands();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].1.aliases, vec!["r", "dice"]);
+        assert_eq!(call(&processor, "!DICE 6").await.unwrap().as_deref(), Some("6"));
+    }
+
+    #[tokio::test]
+    async fn skipped_conflicts_leave_no_dangling_aliases() {
+        let processor = testing::processor(ProcessorConfig {
+            conflict_policy: ConflictPolicy::Skip,
+            ..ProcessorConfig::default()
+        });
+        processor
+            .load_in_process("first", |registrar| registrar.register_command("roll", &["r"], Box::new(Echo)))
+            .unwrap();
+        processor
+            .load_in_process("second", |registrar| {
+                registrar.register_command("dice", &["R", "d"], Box::new(Echo));
+                registrar.register_command("Roll", &["x"], Box::new(Echo));
+            })
+            .unwrap();
+
+        let commands: Vec<(String, String, Vec<String>)> = processor
+            .commands()
+            .into_iter()
+            .map(|(library, command)| (library, command.name, command.aliases))
+            .collect();
+        assert_eq!(
+            commands,
+            vec![
+                ("second".to_string(), "dice".to_string(), vec!["d".to_string()]),
+                ("first".to_string(), "roll".to_string(), vec!["r".to_string()]),
+            ]
+        );
+        assert_eq!(processor.lookup("r").unwrap()._lib_name, "first");
+        assert!(processor.lookup("x").is_none());
+    }
+
+    #[tokio::test]
+    async fn shared_aliases_are_warned_about() {
+        testing::capture_logs();
+        let processor = testing::processor(ProcessorConfig {
+            conflict_policy: ConflictPolicy::Skip,
+            ..ProcessorConfig::default()
+        });
+        processor
+            .load_in_process("alias_owner", |registrar| registrar.register_command("roll", &["r"], Box::new(Echo)))
+            .unwrap();
+        processor
+            .load_in_process("alias_borrower", |registrar| registrar.register_command("rand", &["r"], Box::new(Echo)))
+            .unwrap();
+
+        let warnings: Vec<testing::CapturedLog> = testing::captured_logs()
+            .into_iter()
+            .filter(|log| log.field("library") == Some("alias_borrower"))
+            .collect();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].field("name"), Some("r"));
+        assert_eq!(warnings[0].field("existing_library"), Some("alias_owner"));
+        assert_eq!(
+            warnings[0].message,
+            "alias r of command rand of library alias_borrower shadows alias r of command roll of library alias_owner, skipping it"
+        );
+
+        let index = processor.index.read();
+        assert_eq!(index.len(), 3);
+        assert_eq!(index["r"]._lib_name, "alias_owner");
+        assert!(index["rand"].aliases.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unloaded_libraries_are_closed() {
+        let processor = testing::processor(ProcessorConfig::default());
+        processor
+            .load_in_process("fake", |registrar| registrar.register_command("echo", &["e"], Box::new(Echo)))
+            .unwrap();
+        call(&processor, "!echo hi").await.unwrap();
+        assert_eq!(processor.open_libraries(), vec!["fake"]);
+
+        processor.unload("fake").await.unwrap();
+        // Nothing, not even an alias entry or a finished invocation, may keep it open
+        assert!(processor.open_libraries().is_empty());
+        assert!(processor.lookup("e").is_none());
+    }
+
+    #[tokio::test]
+    async fn unreachable_userservice_degrades_to_a_minimal_user() {
+        let processor = testing::processor(ProcessorConfig {
+            user_retry_delay: Duration::from_millis(1),
+            ..ProcessorConfig::default()
+        });
+        let mut users = FakeUsers::failing(tonic::Code::Unavailable);
+
+        let (user, degraded) = processor.lookup_user(&mut users, testing::CHANNEL_ID).await.unwrap();
+        assert_eq!(user.channel_id, testing::CHANNEL_ID);
+        assert!(degraded);
+    }
+
+    #[tokio::test]
+    async fn other_userservice_errors_skip_the_message() {
+        let processor = testing::processor(ProcessorConfig {
+            user_retry_delay: Duration::from_millis(1),
+            ..ProcessorConfig::default()
+        });
+        let mut users = FakeUsers::failing(tonic::Code::Internal);
+
+        assert!(processor.lookup_user(&mut users, testing::CHANNEL_ID).await.is_none());
     }
 }
@@ -1,9 +1,140 @@
+use std::str::FromStr;
 use fern::{
     colors::{Color, ColoredLevelConfig}
 };
+use log::{kv::{self, Key, Value}, LevelFilter};
+
+/// Output format of the log
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Colored, human readable lines
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {}", s)),
+        }
+    }
+}
+
+/// Which log lines are written, parsed from directives like `RUST_LOG`.
+///
+/// Directives are separated by commas. A level on its own, like `debug`, sets
+/// the level of every module, `module=level` sets the level of a module and
+/// its submodules and a module on its own logs everything of that module, e.g.
+/// `warn,commandservice::loader=trace`. Modules have to be Rust module
+/// paths, and a bare word that is close to a level is rejected as a misspelled
+/// level rather than taken as a module.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogFilter {
+    pub level: LevelFilter,
+    pub modules: Vec<(String, LevelFilter)>,
+}
+
+impl LogFilter {
+    /// Logs every module at the same level
+    pub fn level(level: LevelFilter) -> Self {
+        LogFilter {
+            level,
+            modules: Vec::new(),
+        }
+    }
+}
+
+const LEVELS: &[&str] = &["off", "error", "warn", "info", "debug", "trace"];
+
+fn parse_level(level: &str) -> Result<LevelFilter, String> {
+    LevelFilter::from_str(level.trim()).map_err(|_| format!("unknown log level {}", level))
+}
+
+/// Whether `module` looks like a Rust module path such as `tonic::transport`
+fn is_module_path(module: &str) -> bool {
+    module.split("::").all(|segment| {
+        let mut chars = segment.chars();
+        matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    })
+}
+
+/// Checks a directive without `=` that isn't a level, which logs everything of a module
+fn check_bare_module(module: &str) -> Result<(), String> {
+    if !is_module_path(module) {
+        return Err(format!("{} is neither a log level nor a module", module));
+    }
+    // A misspelled level is a valid module name, which would silently log nothing
+    if !module.contains("::") {
+        if let Some(level) = crate::suggest::closest(&module.to_lowercase(), LEVELS.iter().copied(), 2) {
+            return Err(format!(
+                "unknown log level {}, did you mean {}? Use {}=trace to log everything of a module",
+                module, level, module
+            ));
+        }
+    }
+    Ok(())
+}
+
+impl FromStr for LogFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = LogFilter::level(LevelFilter::Info);
+        for directive in s.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => {
+                    let module = module.trim();
+                    if !is_module_path(module) {
+                        return Err(format!("invalid module {} in log directive {}", module, directive));
+                    }
+                    filter.modules.push((module.to_string(), parse_level(level)?))
+                }
+                None => match parse_level(directive) {
+                    Ok(level) => filter.level = level,
+                    Err(_) => {
+                        check_bare_module(directive)?;
+                        filter.modules.push((directive.to_string(), LevelFilter::Trace))
+                    }
+                },
+            }
+        }
+        Ok(filter)
+    }
+}
+
+tokio::task_local! {
+    /// Id of the chat message the current task is processing, added to every
+    /// log line so all lines of one message can be found together
+    pub static CORRELATION_ID: String;
+}
+
+/// Collects the structured fields of a record
+struct FieldCollector(Vec<(String, String)>);
+
+impl<'kvs> kv::Visitor<'kvs> for FieldCollector {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+pub(crate) fn fields(record: &log::Record) -> Vec<(String, String)> {
+    let mut collector = FieldCollector(Vec::new());
+    let _ = record.key_values().visit(&mut collector);
+    if let Ok(correlation_id) = CORRELATION_ID.try_with(String::clone) {
+        collector.0.push(("correlation_id".to_string(), correlation_id));
+    }
+    collector.0
+}
 
 /// Sets up regular logging
-pub fn setup_log(verbose: bool) {
+pub fn setup_log(filter: &LogFilter, format: LogFormat) {
     let colors_line = ColoredLevelConfig::new()
         .error(Color::Red)
         .warn(Color::Yellow)
@@ -12,29 +143,91 @@ pub fn setup_log(verbose: bool) {
         .trace(Color::BrightBlack);
     let colors_level = colors_line.info(Color::Green);
 
+    let dispatch = filter
+        .modules
+        .iter()
+        .fold(fern::Dispatch::new().level(filter.level), |dispatch, (module, level)| {
+            dispatch.level_for(module.clone(), *level)
+        });
+
+    let dispatch = match format {
+        LogFormat::Text => dispatch.format(move |out, message, record| {
+            let fields: String = fields(record)
+                .iter()
+                .map(|(key, value)| format!(" {}={}", key, value))
+                .collect();
+            out.finish(format_args!(
+                "{color_line}[{date}][{target}][{level}{color_line}] {message}{fields}\x1B[0m",
+                color_line = format_args!(
+                    "\x1B[{}m",
+                    colors_line.get_color(&record.level()).to_fg_str()
+                ),
+                date = chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+                target = record.target(),
+                level = colors_level.color(record.level()),
+                message = message,
+                fields = fields,
+            ));
+        }),
+        LogFormat::Json => dispatch.format(|out, message, record| {
+            let mut line = serde_json::Map::new();
+            line.insert("timestamp".to_string(), chrono::Local::now().to_rfc3339().into());
+            line.insert("level".to_string(), record.level().to_string().into());
+            line.insert("target".to_string(), record.target().into());
+            line.insert("message".to_string(), message.to_string().into());
+            for (key, value) in fields(record) {
+                line.insert(key, value.into());
+            }
+            out.finish(format_args!("{}", serde_json::Value::Object(line)));
+        }),
+    };
+
     fern::Dispatch::new()
-        .chain(
-            fern::Dispatch::new()
-                .level(if verbose {
-                    log::LevelFilter::Debug
-                } else {
-                    log::LevelFilter::Info
-                })
-                .format(move |out, message, record| {
-                    out.finish(format_args!(
-                        "{color_line}[{date}][{target}][{level}{color_line}] {message}\x1B[0m",
-                        color_line = format_args!(
-                            "\x1B[{}m",
-                            colors_line.get_color(&record.level()).to_fg_str()
-                        ),
-                        date = chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
-                        target = record.target(),
-                        level = colors_level.color(record.level()),
-                        message = message,
-                    ));
-                })
-                .chain(std::io::stdout()),
-        )
+        .chain(dispatch.chain(std::io::stdout()))
         .apply()
         .unwrap();
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_bare_level() {
+        assert_eq!("debug".parse(), Ok(LogFilter::level(LevelFilter::Debug)));
+        assert_eq!("".parse(), Ok(LogFilter::level(LevelFilter::Info)));
+    }
+
+    #[test]
+    fn parses_module_directives() {
+        let filter: LogFilter = "warn, commandservice::loader=trace,tonic".parse().unwrap();
+        assert_eq!(filter.level, LevelFilter::Warn);
+        assert_eq!(
+            filter.modules,
+            vec![
+                ("commandservice::loader".to_string(), LevelFilter::Trace),
+                ("tonic".to_string(), LevelFilter::Trace),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_levels() {
+        assert!("loader=loud".parse::<LogFilter>().is_err());
+    }
+
+    #[test]
+    fn rejects_misspelled_bare_levels() {
+        assert!("debgu".parse::<LogFilter>().is_err());
+        assert!("warn,inof".parse::<LogFilter>().is_err());
+        // Spelled as a directive it's still taken as a module
+        assert!("debgu=trace".parse::<LogFilter>().is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_modules() {
+        assert!("my-crate".parse::<LogFilter>().is_err());
+        assert!("loader::=debug".parse::<LogFilter>().is_err());
+        assert!("1st=debug".parse::<LogFilter>().is_err());
+    }
+}
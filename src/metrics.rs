@@ -0,0 +1,123 @@
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Response,
+};
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use tokio_util::sync::CancellationToken;
+
+/// Buckets for command execution times, in seconds
+const EXECUTION_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Prometheus metrics of the command service
+pub struct Metrics {
+    registry: Registry,
+    /// Command invocations by command, library and result
+    pub command_invocations: IntCounterVec,
+    /// Chat messages received from the YouTube service
+    pub messages_processed: IntCounter,
+    /// Time spent executing commands by command and result
+    pub command_execution: HistogramVec,
+    /// User lookups that fell back to a minimal user because the userservice was unavailable
+    pub degraded_user_lookups: IntCounter,
+    /// Libraries that are still open, including unloaded ones that are still in use.
+    /// Updated whenever a library is opened, unloaded or reloaded and on `Ping`
+    pub open_libraries: IntGauge,
+    /// Messages to the chat that were dropped, because the send queue was full or sending kept failing
+    pub dropped_messages: IntCounter,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let command_invocations = IntCounterVec::new(
+            Opts::new("command_invocations_total", "Number of command invocations"),
+            &["command", "library", "result"],
+        )
+        .unwrap();
+        let messages_processed = IntCounter::new(
+            "messages_processed_total",
+            "Number of chat messages received",
+        )
+        .unwrap();
+        let degraded_user_lookups = IntCounter::new(
+            "degraded_user_lookups_total",
+            "Number of user lookups that fell back to a minimal user",
+        )
+        .unwrap();
+        let open_libraries = IntGauge::new(
+            "open_libraries",
+            "Number of libraries that are still open",
+        )
+        .unwrap();
+        let dropped_messages = IntCounter::new(
+            "dropped_messages_total",
+            "Number of messages to the chat that were dropped",
+        )
+        .unwrap();
+        let command_execution = HistogramVec::new(
+            HistogramOpts::new("command_execution_seconds", "Time spent executing commands")
+                .buckets(EXECUTION_BUCKETS.to_vec()),
+            &["command", "result"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(command_invocations.clone())).unwrap();
+        registry.register(Box::new(messages_processed.clone())).unwrap();
+        registry.register(Box::new(command_execution.clone())).unwrap();
+        registry.register(Box::new(degraded_user_lookups.clone())).unwrap();
+        registry.register(Box::new(open_libraries.clone())).unwrap();
+        registry.register(Box::new(dropped_messages.clone())).unwrap();
+
+        Metrics {
+            registry,
+            command_invocations,
+            messages_processed,
+            command_execution,
+            degraded_user_lookups,
+            open_libraries,
+            dropped_messages,
+        }
+    }
+
+    /// Encodes all metrics in the Prometheus text format
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        buffer
+    }
+}
+
+/// Serves the metrics over HTTP until `shutdown` is cancelled, fails if `address` can't be bound
+pub async fn serve(metrics: Arc<Metrics>, address: SocketAddr, shutdown: CancellationToken) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |_| {
+                let metrics = metrics.clone();
+                async move {
+                    let response = Response::builder()
+                        .header(CONTENT_TYPE, TextEncoder::new().format_type())
+                        .body(Body::from(metrics.encode()))
+                        .unwrap();
+                    Ok::<_, Infallible>(response)
+                }
+            }))
+        }
+    });
+
+    hyper::Server::try_bind(&address)?
+        .serve(make_service)
+        .with_graceful_shutdown(async move { shutdown.cancelled().await })
+        .await
+}
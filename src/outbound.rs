@@ -0,0 +1,146 @@
+use async_trait::async_trait;
+use bpp_command_api::traits::YouTubeSendable;
+use futures::stream::BoxStream;
+use log::{error, warn};
+use std::{sync::Arc, time::Duration};
+use tokio::{runtime::Handle, sync::mpsc::{self, error::TrySendError}};
+use tonic::{Code, Status};
+use crate::{config::ProcessorConfig, metrics::Metrics, ratelimit::TokenBucket, services::{ChatMessage, Sendable}};
+
+/// Upper bound for the delay between two attempts to send a message
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Sends the messages of all commands to the chat through a single queue.
+///
+/// Sending only queues a message, a background task sends the queued messages
+/// in order, at most `CS_SEND_RATE` per minute, so many commands running at
+/// once can't get the bot throttled by the platform. Messages that don't fit
+/// into the queue anymore are dropped.
+///
+/// Messages that fail to send with a transient error are retried up to
+/// `CS_SEND_RETRIES` times. Retrying holds up the messages queued behind, so
+/// they stay in order, but never the command that sent the message.
+///
+/// A batch of messages takes a single place in the queue and its messages are
+/// sent right after each other, so the lines of a command aren't interleaved
+/// with messages of other commands. The rate limit still applies to every
+/// message, a batch larger than the burst is spread over time in order. If a
+/// message of a batch is dropped, the rest of the batch is dropped with it.
+#[derive(Clone)]
+pub struct Outbound<T: Sendable> {
+    /// Used for subscribing, sending always goes through the queue
+    inner: T,
+    queue: mpsc::Sender<Vec<String>>,
+    metrics: Arc<Metrics>,
+}
+
+/// How the queued messages are sent
+struct SendPolicy {
+    /// Messages per second, zero if unlimited
+    rate: f64,
+    burst: f64,
+    retries: u32,
+    retry_delay: Duration,
+}
+
+impl<T: Sendable> Outbound<T> {
+    /// Spawns the task that sends the queued messages on `runtime`, it stops
+    /// once every clone of the returned sender is gone
+    pub fn new(sender: T, config: &ProcessorConfig, metrics: Arc<Metrics>, runtime: &Handle) -> Self {
+        let (queue, receiver) = mpsc::channel(config.send_queue);
+        let policy = SendPolicy {
+            // Per second, like the user rate limit
+            rate: config.send_rate / 60.0,
+            burst: config.send_burst.max(1) as f64,
+            retries: config.send_retries,
+            retry_delay: config.send_retry_delay,
+        };
+        runtime.spawn(send_queued(sender.clone(), receiver, policy, metrics.clone()));
+
+        Outbound { inner: sender, queue, metrics }
+    }
+
+    /// Queues a batch of messages, dropping it if the queue is full
+    fn enqueue(&self, batch: Vec<String>) -> Result<(), Status> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        match self.queue.try_send(batch) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(batch)) => {
+                warn!("Too many messages are waiting to be sent, dropping {} messages", batch.len());
+                self.metrics.dropped_messages.inc_by(batch.len() as u64);
+                Err(Status::resource_exhausted("too many messages are waiting to be sent"))
+            }
+            Err(TrySendError::Closed(_)) => Err(Status::unavailable("messages aren't sent anymore")),
+        }
+    }
+}
+
+/// Whether sending might succeed when it's tried again
+fn is_transient(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unavailable | Code::DeadlineExceeded | Code::ResourceExhausted | Code::Aborted
+    )
+}
+
+async fn send_queued<T: Sendable>(mut sender: T, mut receiver: mpsc::Receiver<Vec<String>>, policy: SendPolicy, metrics: Arc<Metrics>) {
+    let mut bucket = TokenBucket::full(policy.burst);
+    while let Some(batch) = receiver.recv().await {
+        for (sent, message) in batch.iter().enumerate() {
+            if !send_with_retries(&mut sender, message, &policy, &mut bucket).await {
+                let dropped = batch.len() - sent;
+                if dropped > 1 {
+                    warn!("Dropping the remaining {} messages of the batch", dropped - 1);
+                }
+                metrics.dropped_messages.inc_by(dropped as u64);
+                break;
+            }
+        }
+    }
+}
+
+/// Sends a message, retrying it after transient errors. Returns false if it was dropped
+async fn send_with_retries<T: Sendable>(sender: &mut T, message: &str, policy: &SendPolicy, bucket: &mut TokenBucket) -> bool {
+    let mut attempt = 0;
+    let mut delay = policy.retry_delay;
+    loop {
+        // Retries count against the rate limit as well
+        if policy.rate > 0.0 {
+            bucket.acquire(policy.rate, policy.burst).await;
+        }
+        let send_result = sender.send_message(message).await;
+        if send_result.is_ok() {
+            return true;
+        }
+        let err = send_result.err().unwrap();
+        if !is_transient(&err) || attempt >= policy.retries {
+            error!("Unable to send message, dropping it after {} attempts: {}", attempt + 1, err);
+            return false;
+        }
+
+        attempt += 1;
+        warn!("Unable to send message, retrying in {:?} (attempt {}/{}): {}", delay, attempt, policy.retries, err);
+        tokio::time::sleep(delay).await;
+        delay = delay.saturating_mul(2).min(MAX_RETRY_DELAY);
+    }
+}
+
+#[async_trait]
+impl<T: Sendable> YouTubeSendable for Outbound<T> {
+    async fn send_message(&mut self, message: &str) -> Result<(), Status> {
+        self.enqueue(vec![message.to_string()])
+    }
+
+    async fn send_messages(&mut self, messages: &[&str]) -> Result<(), Status> {
+        self.enqueue(messages.iter().map(|message| message.to_string()).collect())
+    }
+}
+
+#[async_trait]
+impl<T: Sendable> Sendable for Outbound<T> {
+    async fn subscribe_messages(&mut self) -> Result<BoxStream<'static, Result<ChatMessage, Status>>, Status> {
+        self.inner.subscribe_messages().await
+    }
+}
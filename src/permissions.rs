@@ -0,0 +1,10 @@
+use bpp_command_api::{structs::PermissionLevel, userservice::{BppUser, Role}};
+
+/// Returns the permission level the userservice role of a user grants
+pub fn level_of(user: &BppUser) -> PermissionLevel {
+    match user.role() {
+        Role::Owner => PermissionLevel::Owner,
+        Role::Moderator => PermissionLevel::Moderator,
+        _ => PermissionLevel::Everyone,
+    }
+}
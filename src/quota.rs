@@ -0,0 +1,213 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+/// How often each user may invoke a command within a window
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quota {
+    pub limit: u32,
+    pub window: Duration,
+}
+
+/// Parses a window like `30m`, `1h` or `1d`, plain numbers are seconds
+fn parse_window(window: &str) -> Result<Duration, String> {
+    let window = window.trim();
+    let (number, unit) = match window.char_indices().last() {
+        Some((index, unit)) if unit.is_ascii_alphabetic() => (&window[..index], unit),
+        _ => (window, 's'),
+    };
+    let number: u64 = number.parse().map_err(|_| format!("invalid quota window {}", window))?;
+    let seconds = match unit {
+        's' => number,
+        'm' => number * 60,
+        'h' => number * 60 * 60,
+        'd' => number * 24 * 60 * 60,
+        _ => return Err(format!("unknown unit of quota window {}", window)),
+    };
+    if seconds == 0 {
+        return Err(format!("quota window {} is empty", window));
+    }
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Quotas of the commands, keyed by lowercase primary name.
+///
+/// Parsed from comma-separated `command=limit/window` entries like
+/// `roll=100/1d,dice=10/1h`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Quotas(pub HashMap<String, Quota>);
+
+impl FromStr for Quotas {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut quotas = HashMap::new();
+        for entry in s.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (command, quota) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("quota {} is missing a command", entry))?;
+            let (limit, window) = quota
+                .split_once('/')
+                .ok_or_else(|| format!("quota {} is missing a window", entry))?;
+            let limit = limit
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid limit of quota {}", entry))?;
+            quotas.insert(
+                command.trim().to_lowercase(),
+                Quota {
+                    limit,
+                    window: parse_window(window)?,
+                },
+            );
+        }
+        Ok(Quotas(quotas))
+    }
+}
+
+/// Invocations of a user within the current window of a quota
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuotaUsage {
+    /// Unix timestamp in seconds the window started at
+    pub window_start: u64,
+    pub count: u32,
+}
+
+/// Usage of every command with a quota, keyed by command and then channel id
+pub type UsageMap = BTreeMap<String, BTreeMap<String, QuotaUsage>>;
+
+/// How often `try_use` drops the usage of past windows
+const PRUNE_INTERVAL_SECS: u64 = 60;
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+struct Usage {
+    users: UsageMap,
+    /// When the usage of past windows was last dropped
+    pruned_at: u64,
+    /// Whether anything was counted since the last snapshot
+    changed: bool,
+}
+
+/// Drops the usage of past windows and of commands that no longer have a quota
+fn prune(users: &mut UsageMap, quotas: &HashMap<String, Quota>, now: u64) {
+    users.retain(|command, users| {
+        let window = match quotas.get(command) {
+            Some(quota) => quota.window.as_secs(),
+            None => return false,
+        };
+        let window_start = now - now % window;
+        users.retain(|_, usage| usage.window_start == window_start);
+        !users.is_empty()
+    });
+}
+
+/// Counts how often users invoked commands with a quota.
+///
+/// Windows are aligned to the Unix epoch, so a daily quota resets at midnight
+/// UTC, and the usage can be persisted and picked up again after a restart.
+/// Users who don't come back within a window are dropped every minute, so the
+/// usage only grows with the users of the current windows.
+pub struct QuotaTracker {
+    quotas: HashMap<String, Quota>,
+    usage: Mutex<Usage>,
+}
+
+impl QuotaTracker {
+    pub fn new(quotas: Quotas, usage: UsageMap) -> Self {
+        QuotaTracker {
+            quotas: quotas.0,
+            usage: Mutex::new(Usage {
+                users: usage,
+                pruned_at: 0,
+                changed: false,
+            }),
+        }
+    }
+
+    pub fn quota(&self, command: &str) -> Option<Quota> {
+        self.quotas.get(&command.to_lowercase()).copied()
+    }
+
+    /// Counts an invocation of a command by a user, returns the time until the
+    /// quota resets instead if the user used it up already
+    pub fn try_use(&self, command: &str, channel_id: &str) -> Result<(), Duration> {
+        let command = command.to_lowercase();
+        let quota = match self.quotas.get(&command) {
+            Some(quota) => *quota,
+            None => return Ok(()),
+        };
+        let window = quota.window.as_secs();
+        let now = unix_now();
+        let window_start = now - now % window;
+
+        let mut usage = self.usage.lock();
+        if now >= usage.pruned_at + PRUNE_INTERVAL_SECS {
+            prune(&mut usage.users, &self.quotas, now);
+            usage.pruned_at = now;
+        }
+        let user_usage = usage
+            .users
+            .entry(command)
+            .or_default()
+            .entry(channel_id.to_string())
+            .or_default();
+        if user_usage.window_start != window_start {
+            *user_usage = QuotaUsage { window_start, count: 0 };
+        }
+        if user_usage.count >= quota.limit {
+            return Err(Duration::from_secs(window_start + window - now));
+        }
+        user_usage.count += 1;
+        usage.changed = true;
+        Ok(())
+    }
+
+    /// Returns the usage within the current window of each user of a command
+    pub fn usage(&self, command: &str) -> BTreeMap<String, u32> {
+        let command = command.to_lowercase();
+        let quota = match self.quotas.get(&command) {
+            Some(quota) => *quota,
+            None => return BTreeMap::new(),
+        };
+        let window = quota.window.as_secs();
+        let now = unix_now();
+        let window_start = now - now % window;
+
+        self.usage
+            .lock()
+            .users
+            .get(&command)
+            .map(|users| {
+                users
+                    .iter()
+                    .filter(|(_, usage)| usage.window_start == window_start)
+                    .map(|(channel_id, usage)| (channel_id.clone(), usage.count))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Drops the usage of past windows and returns what's left, to be persisted
+    pub fn snapshot(&self) -> UsageMap {
+        let now = unix_now();
+        let mut usage = self.usage.lock();
+        prune(&mut usage.users, &self.quotas, now);
+        usage.pruned_at = now;
+        usage.changed = false;
+        usage.users.clone()
+    }
+
+    /// Whether anything was counted since the last snapshot
+    pub fn has_changed(&self) -> bool {
+        self.usage.lock().changed
+    }
+}
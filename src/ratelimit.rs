@@ -0,0 +1,81 @@
+use std::{collections::HashMap, hash::Hash, time::{Duration, Instant}};
+use parking_lot::Mutex;
+
+/// Number of tracked keys after which idle buckets get evicted
+const EVICTION_THRESHOLD: usize = 4096;
+
+pub struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn full(burst: f64) -> Self {
+        TokenBucket {
+            tokens: burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, rate: f64, burst: f64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.last_refill = now;
+    }
+
+    /// Waits until the bucket holds a token and takes it
+    pub async fn acquire(&mut self, rate: f64, burst: f64) {
+        self.refill(rate, burst);
+        if self.tokens < 1.0 {
+            tokio::time::sleep(Duration::from_secs_f64((1.0 - self.tokens) / rate)).await;
+            self.refill(rate, burst);
+        }
+        self.tokens -= 1.0;
+    }
+}
+
+/// Token bucket rate limiter with one bucket per key.
+///
+/// Buckets that have refilled completely carry no state worth keeping, so they
+/// are evicted once the number of tracked keys grows too large.
+pub struct RateLimiter<K> {
+    /// Tokens added per second
+    rate: f64,
+    /// Maximum number of tokens in a bucket
+    burst: f64,
+    buckets: Mutex<HashMap<K, TokenBucket>>,
+}
+
+impl<K: Hash + Eq + Clone> RateLimiter<K> {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        RateLimiter {
+            rate,
+            burst: burst.max(1) as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from the bucket of `key`, returns false if the bucket is empty
+    pub fn try_acquire(&self, key: &K) -> bool {
+        let mut buckets = self.buckets.lock();
+
+        if buckets.len() >= EVICTION_THRESHOLD {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, bucket| {
+                bucket.refill(rate, burst);
+                bucket.tokens < burst
+            });
+        }
+
+        let burst = self.burst;
+        let bucket = buckets.entry(key.clone()).or_insert_with(|| TokenBucket::full(burst));
+        bucket.refill(self.rate, self.burst);
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
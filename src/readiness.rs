@@ -0,0 +1,72 @@
+use parking_lot::Mutex;
+use tokio::sync::watch;
+
+/// Conditions that have to hold for the service to process commands
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Readiness {
+    /// At least one library is loaded
+    pub libraries_loaded: bool,
+    /// The message stream of the YouTube service is subscribed
+    pub stream_connected: bool,
+    /// The last user lookup reached the userservice
+    pub userservice_reachable: bool,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.failing().is_empty()
+    }
+
+    /// Describes the conditions that don't hold
+    pub fn failing(&self) -> Vec<&'static str> {
+        let mut failing = Vec::new();
+        if !self.libraries_loaded {
+            failing.push("no libraries are loaded");
+        }
+        if !self.stream_connected {
+            failing.push("the message stream is not connected");
+        }
+        if !self.userservice_reachable {
+            failing.push("the userservice is unreachable");
+        }
+        failing
+    }
+}
+
+/// Tracks the readiness of the service, notifying subscribers whenever it changes
+pub struct ReadinessState {
+    current: Mutex<Readiness>,
+    sender: watch::Sender<Readiness>,
+    // Keeps the channel open, so the state is updated even without subscribers
+    _receiver: watch::Receiver<Readiness>,
+}
+
+impl ReadinessState {
+    pub fn new(initial: Readiness) -> Self {
+        let (sender, _receiver) = watch::channel(initial);
+        ReadinessState {
+            current: Mutex::new(initial),
+            sender,
+            _receiver,
+        }
+    }
+
+    pub fn get(&self) -> Readiness {
+        *self.current.lock()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Readiness> {
+        self.sender.subscribe()
+    }
+
+    /// Changes the readiness, subscribers are only notified if it actually changed
+    pub fn update<F: FnOnce(&mut Readiness)>(&self, update: F) {
+        // The lock is held while sending, so concurrent updates can't be sent out of order
+        let mut current = self.current.lock();
+        let previous = *current;
+        update(&mut current);
+        if *current != previous {
+            let _ = self.sender.send(*current);
+        }
+    }
+}
@@ -1,106 +1,42 @@
-use tonic::transport::Server;
-use ::log::{debug, error, info};
-use crate::{loader::CommandProcessor, log::setup_log};
-use std::{env, net::SocketAddr, sync::Arc};
-
-use commandservice::*;
-
-pub mod log;
-mod loader;
-
-pub mod commandservice {
-    tonic::include_proto!("commandservice");
-}
-
-// Implement your proto here
-// https://github.com/hyperium/tonic/blob/master/examples/helloworld-tutorial.md
-// https://github.com/hyperium/tonic/blob/master/examples/routeguide-tutorial.md
-
-fn ensure_command_directory() {
-    let mut path = env::current_dir().unwrap();
-    path.push("commands");
-    if !path.exists() {
-        std::fs::create_dir(path).unwrap();
-    }
-}
-
-fn load_commands(loader: &CommandProcessor) {
-    // for each file in the commands directory, that is a shared library, load it
-    for entry in std::fs::read_dir("commands").unwrap() {
-        let entry = entry.unwrap();
-        let path = entry.path();
-        if path.is_file() {
-            let file_name = path.file_name().unwrap().to_str().unwrap();
-            #[cfg(target_os = "linux")]
-            if file_name.ends_with(".so") {
-                info!("Loading library: {}", file_name);
-                unsafe {
-                    let load_result = loader.load(path.to_str().unwrap());
-                    if load_result.is_err() {
-                        error!("Error loading library: {}", load_result.err().unwrap());
-                    }
-                }
-            }
-            #[cfg(target_os = "windows")]
-            if file_name.ends_with(".dll") {
-                info!("Loading library: {}", file_name);
-                unsafe {
-                    let load_result = loader.load(file_name);
-                    if load_result.is_err() {
-                        error!("Error loading library: {}", load_result.err().unwrap());
-                    }
-                }
-            }
-            #[cfg(target_os = "macos")]
-            if file_name.ends_with(".dylib") {
-                info!("Loading library: {}", file_name);
-                unsafe {
-                    let load_result = loader.load(file_name);
-                    if load_result.is_err() {
-                        error!("Error loading library: {}", load_result.err().unwrap());
-                    }
-                }
-            }
+use log::debug;
+use clap::Parser;
+use commandservice::{cli::{self, Cli}, config::{env_or, ServiceConfig}, log::{setup_log, LogFormat}, telemetry};
+
+/// Resolves once the process is asked to stop via Ctrl-C or SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Unable to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
         }
     }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    setup_log(env::var_os("DEBUG").is_some());
+    let cli = Cli::parse();
+    setup_log(&cli.log_filter(), env_or("CS_LOG_FORMAT", LogFormat::Text));
+    telemetry::setup_tracing()?;
     debug!("Debug mode activated!");
 
-    let youtube_address = env::var("YTS_GRPC_ADDRESS").expect("YTS_GRPC_ADDRESS must be set");
-    let user_address = env::var("US_GRPC_ADDRESS").expect("US_GRPC_ADDRESS must be set");
-
-    let commandservice_address = env::var("CS_GRPC_ADDRESS");
-    let commandservice_address: SocketAddr = if commandservice_address.is_err() {
-        "0.0.0.0:50051".parse()?
-    } else {
-        commandservice_address.unwrap().parse()?
-    };
-
-    let youtube_client = bpp_command_api::youtubeservice::you_tube_service_client::YouTubeServiceClient::connect(youtube_address).await?;
-    let user_client = bpp_command_api::userservice::user_service_client::UserServiceClient::connect(user_address).await?;
-
-    info!("Loading commands");
-    let loader = CommandProcessor::new(youtube_client, user_client);
-    let loader_arc = Arc::new(loader);
-    ensure_command_directory();
-    load_commands(&loader_arc);
-
-    let fetch_loader = loader_arc.clone();
-    let (_, _) = tokio::join!(
-        async move {
-            let server_loader = loader_arc.clone();
-            Server::builder()
-            .add_service(commandservice::command_service_server::CommandServiceServer::new(loader::CommandServiceServer {
-                processor: server_loader,
-            }))
-            .serve(commandservice_address).await
-        },
-        fetch_loader.fetch_messages()
-    );
+    let commands_directories = cli.commands_directories();
+    match cli.command {
+        Some(cli::Command::Validate) => {
+            let valid = commandservice::validate(&commands_directories).await?;
+            std::process::exit(if valid { 0 } else { 1 });
+        }
+        Some(cli::Command::List) => return commandservice::list(&commands_directories),
+        None => {}
+    }
 
-    Ok(())
+    let run_result = commandservice::run(ServiceConfig::from_cli(&cli)?, shutdown_signal(), None).await;
+    telemetry::shutdown_tracing();
+    run_result
 }
@@ -0,0 +1,52 @@
+use async_trait::async_trait;
+use bpp_command_api::{
+    traits::YouTubeSendable,
+    youtubeservice::you_tube_service_client::YouTubeServiceClient,
+};
+use futures::{stream::BoxStream, StreamExt};
+use tonic::{transport::Channel, Request, Status};
+
+// Lives in the API crate, which implements it for the userservice client, so
+// commands look users up through the `ServiceDirectory` as well
+pub use bpp_command_api::traits::UserLookup;
+
+/// A chat message received from a chat platform
+#[derive(Clone, Debug)]
+pub struct ChatMessage {
+    /// Platform id of the user that sent the message
+    pub channel_id: String,
+    pub message: String,
+}
+
+/// A chat platform commands are received from and replies are sent to.
+///
+/// Sending goes through [`YouTubeSendable`], which commands already use through
+/// the `ServiceDirectory`, so a Twitch or Discord backend only has to implement
+/// it together with the subscription.
+#[async_trait]
+pub trait Sendable: YouTubeSendable + Clone + Send + Sync + 'static {
+    /// Subscribes to the chat messages of the platform
+    async fn subscribe_messages(&mut self) -> Result<BoxStream<'static, Result<ChatMessage, Status>>, Status>;
+}
+
+#[async_trait]
+impl Sendable for YouTubeServiceClient<Channel> {
+    async fn subscribe_messages(&mut self) -> Result<BoxStream<'static, Result<ChatMessage, Status>>, Status> {
+        let stream = YouTubeServiceClient::subscribe_messages(self, Request::new(()))
+            .await?
+            .into_inner();
+        Ok(stream
+            .map(|message| {
+                message.map(|message| ChatMessage {
+                    channel_id: message.channel_id,
+                    message: message.message,
+                })
+            })
+            .boxed())
+    }
+}
+
+/// A [`UserLookup`] the processor can hand a clone of to every worker and task
+pub trait UserService: UserLookup + Clone + Sync + 'static {}
+
+impl<U: UserLookup + Clone + Sync + 'static> UserService for U {}
@@ -0,0 +1,82 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use futures::{
+    future::{BoxFuture, Shared},
+    FutureExt,
+};
+use parking_lot::Mutex;
+
+/// Lookups that are currently running, keyed by what is looked up.
+///
+/// Asking for a key that is already being looked up waits for that lookup and
+/// shares its result instead of starting another one. The lookup keeps running
+/// as long as anyone still waits for it, even if the caller that started it
+/// went away.
+pub struct SingleFlight<V: Clone> {
+    in_flight: Mutex<HashMap<String, (u64, Shared<BoxFuture<'static, V>>)>>,
+    next_id: AtomicU64,
+}
+
+impl<V: Clone> Default for SingleFlight<V> {
+    fn default() -> Self {
+        SingleFlight {
+            in_flight: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<V: Clone + Send + Sync + 'static> SingleFlight<V> {
+    /// Returns the result of the running lookup of `key`, or starts one with `lookup`
+    pub async fn run<F, Fut>(&self, key: &str, lookup: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V> + Send + 'static,
+    {
+        let (id, flight) = self
+            .in_flight
+            .lock()
+            .entry(key.to_string())
+            .or_insert_with(|| (self.next_id.fetch_add(1, Ordering::Relaxed), lookup().boxed().shared()))
+            .clone();
+        let result = flight.await;
+
+        // Whoever gets here first removes the finished lookup, unless a new one already replaced it
+        let mut in_flight = self.in_flight.lock();
+        if in_flight.get(key).map_or(false, |(running, _)| *running == id) {
+            in_flight.remove(key);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::{atomic::AtomicUsize, Arc}, time::Duration};
+
+    #[tokio::test]
+    async fn concurrent_lookups_share_one_call() {
+        let flights = SingleFlight::default();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let lookup = || {
+            let calls = calls.clone();
+            async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                "user".to_string()
+            }
+        };
+
+        let (first, second) = tokio::join!(flights.run("channel", lookup), flights.run("channel", lookup));
+        assert_eq!((first.as_str(), second.as_str()), ("user", "user"));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Finished lookups aren't reused
+        flights.run("channel", lookup).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,41 @@
+use std::{collections::BTreeSet, fs, io, path::Path};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use crate::quota::UsageMap;
+
+/// State changed at runtime that has to survive a restart
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PersistedState {
+    /// Lowercase primary names of the commands an operator disabled
+    #[serde(default)]
+    pub disabled_commands: BTreeSet<String>,
+    /// Usage of the commands with a quota within their current window
+    #[serde(default)]
+    pub quota_usage: UsageMap,
+}
+
+impl PersistedState {
+    /// Reads the state file, falling back to the default state if it's missing or corrupt
+    pub fn load(path: &Path) -> Self {
+        let contents = fs::read_to_string(path);
+        if contents.is_err() {
+            warn!("Unable to read state file {}, enabling all commands: {}", path.display(), contents.err().unwrap());
+            return PersistedState::default();
+        }
+
+        let state = serde_json::from_str(&contents.unwrap());
+        if state.is_err() {
+            warn!("State file {} is corrupt, enabling all commands: {}", path.display(), state.err().unwrap());
+            return PersistedState::default();
+        }
+        state.unwrap()
+    }
+
+    /// Writes the state file, replacing it at once so a crash can't leave it half written
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let contents = serde_json::to_vec_pretty(self)?;
+        let temporary_path = path.with_extension("tmp");
+        fs::write(&temporary_path, contents)?;
+        fs::rename(&temporary_path, path)
+    }
+}
@@ -0,0 +1,73 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
+};
+use parking_lot::{Mutex, RwLock};
+
+/// Usage counters of a single command
+#[derive(Default)]
+pub struct CommandStats {
+    pub invocations: AtomicU64,
+    pub successes: AtomicU64,
+    pub failures: AtomicU64,
+    /// Unix timestamp of the last invocation in milliseconds, zero if never invoked
+    pub last_invoked: AtomicI64,
+    /// Error of the last invocation, cleared once the command succeeds again
+    pub last_error: Mutex<Option<LastError>>,
+}
+
+/// Error of a failed command invocation
+#[derive(Clone)]
+pub struct LastError {
+    pub message: String,
+    /// Unix timestamp of the failed invocation in milliseconds
+    pub at: i64,
+}
+
+/// Usage counters of all commands, keyed by primary command name.
+///
+/// The counters live outside of the loaded libraries, so they survive
+/// libraries being loaded and unloaded.
+#[derive(Default)]
+pub struct StatsRegistry {
+    commands: RwLock<HashMap<String, Arc<CommandStats>>>,
+}
+
+impl StatsRegistry {
+    fn get_or_insert(&self, command: &str) -> Arc<CommandStats> {
+        if let Some(stats) = self.commands.read().get(command) {
+            return stats.clone();
+        }
+        self.commands
+            .write()
+            .entry(command.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Records an execution of a command, `error` is `None` if it succeeded
+    pub fn record(&self, command: &str, error: Option<String>) {
+        let stats = self.get_or_insert(command);
+        let now = chrono::Utc::now().timestamp_millis();
+        stats.invocations.fetch_add(1, Ordering::Relaxed);
+        if error.is_none() {
+            stats.successes.fetch_add(1, Ordering::Relaxed);
+        } else {
+            stats.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        stats.last_invoked.store(now, Ordering::Relaxed);
+        *stats.last_error.lock() = error.map(|message| LastError { message, at: now });
+    }
+
+    /// Returns the counters of every command that has been invoked
+    pub fn snapshot(&self) -> Vec<(String, Arc<CommandStats>)> {
+        self.commands
+            .read()
+            .iter()
+            .map(|(command, stats)| (command.clone(), stats.clone()))
+            .collect()
+    }
+}
@@ -0,0 +1,40 @@
+use std::env;
+use log::info;
+use tracing_subscriber::layer::SubscriberExt;
+
+/// Exports tracing spans over OTLP if `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+///
+/// Without an endpoint no subscriber is installed and spans cost next to nothing.
+pub fn setup_tracing() -> Result<(), Box<dyn std::error::Error>> {
+    let endpoint = env::var("OTEL_EXPORTER_OTLP_ENDPOINT");
+    if endpoint.is_err() {
+        return Ok(());
+    }
+    let endpoint = endpoint.unwrap();
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint.clone()),
+        )
+        .with_trace_config(opentelemetry::sdk::trace::config().with_resource(
+            opentelemetry::sdk::Resource::new(vec![opentelemetry::KeyValue::new(
+                "service.name",
+                "commandservice",
+            )]),
+        ))
+        .install_batch(opentelemetry::runtime::Tokio)?;
+
+    let subscriber = tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+    tracing::subscriber::set_global_default(subscriber)?;
+
+    info!("Exporting traces to {}", endpoint);
+    Ok(())
+}
+
+/// Flushes the spans that haven't been exported yet
+pub fn shutdown_tracing() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
@@ -0,0 +1,189 @@
+//! In-memory fakes of the downstream services, for exercising the processor in tests.
+//!
+//! Command libraries can use them for their own tests by turning on the
+//! `testing` feature.
+
+use std::sync::Arc;
+use async_trait::async_trait;
+use bpp_command_api::{
+    structs::{Message, ServiceDirectory},
+    traits::{Command, YouTubeSendable},
+    userservice::BppUser,
+    CommandError,
+};
+use futures::{stream::BoxStream, StreamExt};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use parking_lot::{const_mutex, Mutex};
+use tonic::{Code, Status};
+use crate::{
+    args,
+    config::ProcessorConfig,
+    loader::CommandProcessor,
+    services::{ChatMessage, Sendable, UserLookup},
+};
+
+/// Channel id of the user [`message`] builds messages of
+pub const CHANNEL_ID: &str = "UC_test";
+
+/// A chat platform that records what is sent to it and never receives anything
+#[derive(Clone, Default)]
+pub struct FakeChat {
+    sent: Arc<Mutex<Vec<String>>>,
+}
+
+impl FakeChat {
+    /// Returns the messages sent so far, shared between all clones
+    pub fn sent(&self) -> Vec<String> {
+        self.sent.lock().clone()
+    }
+}
+
+#[async_trait]
+impl YouTubeSendable for FakeChat {
+    async fn send_message(&mut self, message: &str) -> Result<(), Status> {
+        self.sent.lock().push(message.to_string());
+        Ok(())
+    }
+
+    async fn send_messages(&mut self, messages: &[&str]) -> Result<(), Status> {
+        self.sent.lock().extend(messages.iter().map(|message| message.to_string()));
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sendable for FakeChat {
+    async fn subscribe_messages(&mut self) -> Result<BoxStream<'static, Result<ChatMessage, Status>>, Status> {
+        Ok(futures::stream::pending().boxed())
+    }
+}
+
+/// A userservice that knows every user, or fails every lookup with the same code
+#[derive(Clone, Default)]
+pub struct FakeUsers {
+    error: Option<Code>,
+}
+
+impl FakeUsers {
+    /// A userservice that fails every lookup with `code`
+    pub fn failing(code: Code) -> Self {
+        FakeUsers { error: Some(code) }
+    }
+}
+
+#[async_trait]
+impl UserLookup for FakeUsers {
+    async fn get_user(&mut self, channel_id: &str) -> Result<BppUser, Status> {
+        match self.error {
+            Some(code) => Err(Status::new(code, "fake userservice failure")),
+            None => Ok(BppUser {
+                channel_id: channel_id.to_string(),
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+/// Builds a processor that talks to fakes of both services
+pub fn processor(config: ProcessorConfig) -> Arc<CommandProcessor<FakeChat, FakeUsers>> {
+    Arc::new(CommandProcessor::new(FakeChat::default(), FakeUsers::default(), config))
+}
+
+/// Builds the command message of a chat message with the prefix `!`
+pub fn message(text: &str) -> Message {
+    let user = BppUser {
+        channel_id: CHANNEL_ID.to_string(),
+        ..Default::default()
+    };
+    let mut message = Message::with_prefix(user.into(), text.to_string(), "!");
+    message.arguments = args::command_arguments(text, "!");
+    message
+}
+
+/// A command that replies with its arguments
+#[derive(Clone)]
+pub struct Echo;
+
+#[async_trait]
+impl Command for Echo {
+    async fn execute(&self, _message: Message, _service_directory: &mut ServiceDirectory) -> Result<(), CommandError> {
+        Ok(())
+    }
+
+    async fn execute_with_reply(
+        &self,
+        message: Message,
+        _service_directory: &mut ServiceDirectory,
+    ) -> Result<Option<String>, CommandError> {
+        Ok(Some(message.arguments.join(" ")))
+    }
+}
+
+/// A command that panics whenever it runs
+#[derive(Clone)]
+pub struct Panics;
+
+#[async_trait]
+impl Command for Panics {
+    async fn execute(&self, _message: Message, _service_directory: &mut ServiceDirectory) -> Result<(), CommandError> {
+        panic!("command panicked on purpose")
+    }
+}
+
+/// A warning or error caught by [`capture_logs`]
+#[derive(Clone, Debug)]
+pub struct CapturedLog {
+    pub level: Level,
+    pub message: String,
+    /// The structured fields of the record
+    pub fields: Vec<(String, String)>,
+}
+
+impl CapturedLog {
+    /// Returns the value of a structured field
+    pub fn field(&self, key: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field == key)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+static CAPTURED_LOGS: Mutex<Vec<CapturedLog>> = const_mutex(Vec::new());
+
+struct CaptureLogger;
+
+impl Log for CaptureLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Warn
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            CAPTURED_LOGS.lock().push(CapturedLog {
+                level: record.level(),
+                message: record.args().to_string(),
+                fields: crate::log::fields(record),
+            });
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Starts capturing the warnings and errors of every thread, does nothing if
+/// another logger is installed already.
+pub fn capture_logs() {
+    static LOGGER: CaptureLogger = CaptureLogger;
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Warn);
+    }
+}
+
+/// Returns everything captured so far.
+///
+/// Tests run in parallel and share the log, so they have to pick out their own
+/// records, e.g. by the names of their libraries.
+pub fn captured_logs() -> Vec<CapturedLog> {
+    CAPTURED_LOGS.lock().clone()
+}
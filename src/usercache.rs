@@ -0,0 +1,80 @@
+use std::{collections::HashMap, time::{Duration, Instant}};
+use bpp_command_api::userservice::BppUser;
+use parking_lot::Mutex;
+
+struct CacheEntry {
+    user: BppUser,
+    fetched_at: Instant,
+    last_used: Instant,
+}
+
+/// Userservice lookups by channel id, kept for a limited time.
+///
+/// Once the cache is full, the least recently used user is evicted.
+pub struct UserCache {
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl UserCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        UserCache {
+            ttl,
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether lookups are cached at all
+    pub fn is_enabled(&self) -> bool {
+        self.ttl > Duration::ZERO && self.capacity > 0
+    }
+
+    /// Returns the cached user, if it was fetched within the TTL
+    pub fn get(&self, channel_id: &str) -> Option<BppUser> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let mut entries = self.entries.lock();
+        let entry = entries.get_mut(channel_id)?;
+        if entry.fetched_at.elapsed() >= self.ttl {
+            entries.remove(channel_id);
+            return None;
+        }
+        entry.last_used = Instant::now();
+        Some(entry.user.clone())
+    }
+
+    pub fn insert(&self, channel_id: &str, user: BppUser) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut entries = self.entries.lock();
+        if entries.len() >= self.capacity && !entries.contains_key(channel_id) {
+            let ttl = self.ttl;
+            entries.retain(|_, entry| entry.fetched_at.elapsed() < ttl);
+            if entries.len() >= self.capacity {
+                let least_recently_used = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(channel_id, _)| channel_id.clone());
+                if let Some(least_recently_used) = least_recently_used {
+                    entries.remove(&least_recently_used);
+                }
+            }
+        }
+
+        let now = Instant::now();
+        entries.insert(
+            channel_id.to_string(),
+            CacheEntry {
+                user,
+                fetched_at: now,
+                last_used: now,
+            },
+        );
+    }
+}
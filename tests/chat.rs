@@ -0,0 +1,199 @@
+//! Runs the real processor against fakes of the youtubeservice and the
+//! userservice, served over gRPC on ephemeral ports.
+
+use std::{net::SocketAddr, pin::Pin, sync::Arc, time::Duration};
+use async_trait::async_trait;
+use bpp_command_api::{
+    structs::{Message, ServiceDirectory},
+    traits::Command,
+    userservice::{
+        user_service_client::UserServiceClient,
+        user_service_server::{UserService, UserServiceServer},
+        BppUser,
+    },
+    youtubeservice::{
+        you_tube_service_client::YouTubeServiceClient,
+        you_tube_service_server::{YouTubeService, YouTubeServiceServer},
+        YouTubeChatMessage,
+    },
+    CommandError,
+};
+use commandservice::{
+    commandservice::command_service_client::CommandServiceClient,
+    config::{Keepalive, ListenAddress, ProcessorConfig, ServiceConfig},
+    loader::CommandProcessor,
+};
+use futures::{Stream, StreamExt};
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, oneshot},
+};
+use tokio_stream::wrappers::TcpListenerStream;
+use tokio_util::sync::CancellationToken;
+use tonic::{transport::Server, Request, Response, Status};
+
+const CHANNEL_ID: &str = "UC_test";
+
+/// How long to wait for a reply before giving up
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A youtubeservice that sends a fixed set of chat messages to every
+/// subscriber and forwards everything sent to the chat
+struct FakeYouTube {
+    messages: Vec<String>,
+    sent: mpsc::UnboundedSender<String>,
+}
+
+#[async_trait]
+impl YouTubeService for FakeYouTube {
+    type SubscribeMessagesStream = Pin<Box<dyn Stream<Item = Result<YouTubeChatMessage, Status>> + Send + Sync>>;
+
+    async fn subscribe_messages(&self, _: Request<()>) -> Result<Response<Self::SubscribeMessagesStream>, Status> {
+        let messages: Vec<Result<YouTubeChatMessage, Status>> = self
+            .messages
+            .iter()
+            .map(|message| {
+                Ok(YouTubeChatMessage {
+                    channel_id: CHANNEL_ID.to_string(),
+                    message: message.clone(),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        // Stays subscribed like the real chat, which is only quiet for a while
+        let stream = futures::stream::iter(messages).chain(futures::stream::pending());
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn send_message(&self, request: Request<YouTubeChatMessage>) -> Result<Response<()>, Status> {
+        let _ = self.sent.send(request.into_inner().message);
+        Ok(Response::new(()))
+    }
+}
+
+/// A userservice that knows every user
+struct FakeUsers;
+
+#[async_trait]
+impl UserService for FakeUsers {
+    async fn get_user_by_id(&self, request: Request<String>) -> Result<Response<BppUser>, Status> {
+        Ok(Response::new(BppUser {
+            channel_id: request.into_inner(),
+            ..Default::default()
+        }))
+    }
+}
+
+/// A command that replies with its arguments
+#[derive(Clone)]
+struct Echo;
+
+#[async_trait]
+impl Command for Echo {
+    async fn execute(&self, _message: Message, _service_directory: &mut ServiceDirectory) -> Result<(), CommandError> {
+        Ok(())
+    }
+
+    async fn execute_with_reply(
+        &self,
+        message: Message,
+        _service_directory: &mut ServiceDirectory,
+    ) -> Result<Option<String>, CommandError> {
+        Ok(Some(message.arguments.join(" ")))
+    }
+}
+
+/// Serves both fakes on ephemeral ports, returns their addresses and the
+/// messages sent to the chat
+async fn serve_fakes(messages: &[&str]) -> (SocketAddr, SocketAddr, mpsc::UnboundedReceiver<String>) {
+    let (sent, received) = mpsc::unbounded_channel();
+    let youtube = FakeYouTube {
+        messages: messages.iter().map(|message| message.to_string()).collect(),
+        sent,
+    };
+
+    let youtube_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let youtube_address = youtube_listener.local_addr().unwrap();
+    tokio::spawn(
+        Server::builder()
+            .add_service(YouTubeServiceServer::new(youtube))
+            .serve_with_incoming(TcpListenerStream::new(youtube_listener)),
+    );
+
+    let user_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let user_address = user_listener.local_addr().unwrap();
+    tokio::spawn(
+        Server::builder()
+            .add_service(UserServiceServer::new(FakeUsers))
+            .serve_with_incoming(TcpListenerStream::new(user_listener)),
+    );
+
+    (youtube_address, user_address, received)
+}
+
+#[tokio::test]
+async fn commands_reply_through_the_youtubeservice() {
+    let (youtube_address, user_address, mut sent) = serve_fakes(&["hello", "!echo hi there"]).await;
+
+    let youtube_client = YouTubeServiceClient::connect(format!("http://{}", youtube_address)).await.unwrap();
+    let user_client = UserServiceClient::connect(format!("http://{}", user_address)).await.unwrap();
+    let processor = Arc::new(CommandProcessor::new(youtube_client, user_client, ProcessorConfig::default()));
+    processor
+        .load_in_process("dummy", |registrar| registrar.register_command("echo", &[], Box::new(Echo)))
+        .unwrap();
+
+    let shutdown = CancellationToken::new();
+    let (reply, _) = tokio::join!(
+        async {
+            let reply = tokio::time::timeout(TIMEOUT, sent.recv()).await;
+            shutdown.cancel();
+            reply
+        },
+        processor.fetch_messages(shutdown.clone()),
+    );
+    assert_eq!(reply.unwrap().as_deref(), Some("hi there"));
+    // Only the command was answered
+    assert!(sent.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn run_serves_on_the_bound_address() {
+    let (youtube_address, user_address, _sent) = serve_fakes(&[]).await;
+
+    let config = ServiceConfig {
+        youtube_address: format!("http://{}", youtube_address),
+        user_address: format!("http://{}", user_address),
+        listen: ListenAddress::Tcp("127.0.0.1:0".parse().unwrap()),
+        commands_directories: Vec::new(),
+        connect_attempts: 1,
+        connect_delay: Duration::from_millis(10),
+        hot_reload_debounce: None,
+        metrics_address: None,
+        shutdown_timeout: Duration::from_secs(1),
+        keepalive: Keepalive {
+            interval: None,
+            timeout: Duration::from_secs(1),
+        },
+        processor: ProcessorConfig::default(),
+    };
+    let (bound, bound_address) = oneshot::channel();
+    let (stop, stopped) = oneshot::channel::<()>();
+    let (result, _) = tokio::join!(
+        commandservice::run(config, async move {
+            let _ = stopped.await;
+        }, Some(bound)),
+        async move {
+            let address = match tokio::time::timeout(TIMEOUT, bound_address).await.unwrap().unwrap() {
+                ListenAddress::Tcp(address) => address,
+                address => panic!("bound to {}", address),
+            };
+            assert_ne!(address.port(), 0);
+
+            let mut client = CommandServiceClient::connect(format!("http://{}", address)).await.unwrap();
+            let ping = client.ping(Request::new(())).await.unwrap().into_inner();
+            assert_eq!(ping.loaded_libraries, 0);
+            stop.send(()).unwrap();
+        },
+    );
+    assert!(result.is_ok());
+}